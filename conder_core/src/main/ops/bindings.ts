@@ -84,8 +84,10 @@ export type Op = {kind: "negatePrev", data: null} |
 }} |
 {kind: "extractFields", data: string[][]} |
 {kind: "equal", data: null} |
+{kind: "notEqual", data: null} |
 {kind: "less", data: null} |
 {kind: "lesseq", data: null} |
+{kind: "greater", data: null} |
 {kind: "boolAnd", data: null} |
 {kind: "boolOr", data: null} |
 {kind: "assertHeapLen", data: number} |
@@ -102,21 +104,195 @@ export type Op = {kind: "negatePrev", data: null} |
 {kind: "lock", data: null} |
 {kind: "release", data: null} |
 {kind: "signRole", data: null} |
-{kind: "getType", data: null};export type Schema = {kind: "Object", data: Record<string, Schema>} |
+{kind: "getType", data: null} |
+{kind: "queryStorePage", data: {
+    store: string,
+    project: { [K in string]: InterpreterType},
+    options: QueryOptions,
+}} |
+{kind: "enforceSchemaOrReject", data: {
+    schema: Schema,
+    heap_pos: number,
+}} |
+{kind: "aggregateStore", data: {
+    store: string,
+    pipeline: Stage[],
+}} |
+{kind: "aggregateArray", data: Stage[]} |
+{kind: "openStoreCursor", data: [string, { [K in string]: InterpreterType}]} |
+{kind: "nextInCursor", data: null} |
+{kind: "returnCursor", data: null} |
+{kind: "now", data: null} |
+{kind: "formatTimestamp", data: string} |
+{kind: "parseTimestamp", data: string} |
+{kind: "bytesToString", data: string} |
+{kind: "stringToBytes", data: string} |
+{kind: "toDecimal", data: null} |
+{kind: "roundDecimal", data: {
+    places: number,
+    mode: RoundingMode,
+}} |
+{kind: "nMod", data: null} |
+{kind: "nIntDivide", data: null} |
+{kind: "nPow", data: null} |
+{kind: "nAbs", data: null} |
+{kind: "nMin", data: null} |
+{kind: "nMax", data: null} |
+{kind: "nFloor", data: null} |
+{kind: "nCeil", data: null} |
+{kind: "nRound", data: null} |
+{kind: "stringLen", data: null} |
+{kind: "stringSlice", data: null} |
+{kind: "stringIndexOf", data: null} |
+{kind: "stringContains", data: null} |
+{kind: "stringStartsWith", data: null} |
+{kind: "stringEndsWith", data: null} |
+{kind: "stringSplit", data: null} |
+{kind: "stringTrim", data: null} |
+{kind: "stringUpper", data: null} |
+{kind: "stringLower", data: null} |
+{kind: "stringReplace", data: {
+    all: boolean,
+}} |
+{kind: "regexMatch", data: string} |
+{kind: "regexCapture", data: string} |
+{kind: "parseInt", data: null} |
+{kind: "parseDouble", data: null} |
+{kind: "arraySlice", data: null} |
+{kind: "arrayConcat", data: null} |
+{kind: "arrayReverse", data: null} |
+{kind: "arrayContains", data: null} |
+{kind: "arrayIndexOf", data: null} |
+{kind: "arraySort", data: SortKey[]} |
+{kind: "arrayDedup", data: null} |
+{kind: "arrayRemove", data: null} |
+{kind: "arrayMap", data: Callback} |
+{kind: "arrayFilter", data: Callback} |
+{kind: "arrayReduce", data: Callback} |
+{kind: "arrayAny", data: Callback} |
+{kind: "arrayAll", data: Callback} |
+{kind: "arrayFind", data: Callback} |
+{kind: "objectMerge", data: {
+    deep: boolean,
+}} |
+{kind: "objectDelete", data: null} |
+{kind: "objectHasKey", data: null} |
+{kind: "objectValues", data: null} |
+{kind: "objectEntries", data: null} |
+{kind: "objectPick", data: null} |
+{kind: "objectOmit", data: null} |
+{kind: "getPath", data: {
+    negative_indexes: boolean,
+}} |
+{kind: "setPath", data: {
+    negative_indexes: boolean,
+}} |
+{kind: "revokeRole", data: string} |
+{kind: "roleRevoked", data: string};
+
+export type Callback = {kind: "procedure", data: string} |
+{kind: "ops", data: Op[]};
+
+export type RoundingMode = "halfEven" |
+"halfUp" |
+"halfDown" |
+"up" |
+"down" |
+"ceiling" |
+"floor";
+
+export interface Procedure {
+    ops: Op[],
+    params: Schema[] | null,
+    returns: Schema | null,
+}
+
+export interface QueryOptions {
+    sort: SortKey[],
+    limit: number | null,
+    skip: number | null,
+}
+
+export interface SortKey {
+    field: string,
+    ascending: boolean,
+}
+
+export type Stage = {kind: "Match", data: { [K in string]: InterpreterType}} |
+{kind: "Group", data: {
+    by: string[],
+    fields: GroupField[],
+}} |
+{kind: "Sort", data: SortKey[]} |
+{kind: "Limit", data: number} |
+{kind: "Skip", data: number} |
+{kind: "Project", data: { [K in string]: InterpreterType}} |
+{kind: "Lookup", data: {
+    from: string,
+    local_field: string,
+    foreign_field: string,
+    as_field: string,
+}};
+
+export interface GroupField {
+    name: string,
+    acc: Accumulator,
+}
+
+export type Accumulator = {kind: "sum", data: string} |
+{kind: "avg", data: string} |
+{kind: "min", data: string} |
+{kind: "max", data: string} |
+{kind: "push", data: string} |
+{kind: "first", data: string} |
+{kind: "count", data: null};
+
+export interface Store {
+    schema: Schema,
+    indexes: Index[],
+}
+
+export interface Index {
+    keys: SortKey[],
+    unique: boolean,
+    expire_after_seconds: number | null,
+}export type Schema = {kind: "Object", data: Record<string, Schema>} |
+{kind: "PolicyObject", data: [Record<string, Schema>, ObjectPolicy]} |
 {kind: "Role", data: [string, Schema[]]} |
 {kind: "Array", data: Schema[]} |
 {kind: "Union", data: Schema[]} |
 {kind: "Map", data: Schema[]} |
+{kind: "Refined", data: [Schema[], Constraint[]]} |
 {kind: "TypeAlias", data: string} |
 {kind: "double", data: null} |
 {kind: "int", data: null} |
 {kind: "string", data: null} |
 {kind: "bool", data: null} |
+{kind: "decimal", data: null} |
+{kind: "timestamp", data: null} |
+{kind: "bytes", data: null} |
 {kind: "Any", data: null} |
-{kind: "none", data: null};export type InterpreterType = number |
+{kind: "none", data: null};
+
+export type ObjectPolicy = "closed" |
+"open" |
+"strip";
+
+export type Constraint = {kind: "min", data: number} |
+{kind: "max", data: number} |
+{kind: "minLength", data: number} |
+{kind: "maxLength", data: number} |
+{kind: "pattern", data: string} |
+{kind: "oneOf", data: InterpreterType[]} |
+{kind: "minItems", data: number} |
+{kind: "maxItems", data: number} |
+{kind: "uniqueItems", data: null};export type InterpreterType = number |
 number |
 boolean |
 string |
+{$numberDecimal: string} |
+{$date: string} |
+{$binary: string} |
 InterpreterType[] |
 { [K in string]: InterpreterType} |
 null;
\ No newline at end of file
@@ -1,12 +1,16 @@
 import * as bind from './bindings'
 export type StrongServerEnv = {
-    PROCEDURES: Record<string, bind.Op[]>,
+    PROCEDURES: Record<string, bind.Op[] | bind.Procedure>,
     PRIVATE_PROCEDURES?: string[],
     SCHEMAS: Record<string, bind.Schema>,
-    STORES: Record<string, bind.Schema>,
+    STORES: Record<string, bind.Schema | bind.Store>,
     ETCD_URL?: string,
-    PRIVATE_KEY: Uint8Array,
-    PUBLIC_KEY: Uint8Array,
+    PRIVATE_KEY?: Uint8Array,
+    PRIVATE_KEY_FILE?: string,
+    PUBLIC_KEY?: Uint8Array,
+    PUBLIC_KEY_FILE?: string,
+    VERIFICATION_KEYS_DIR?: string,
+    MIN_ROLE_VERSION?: number,
     MONGO_CONNECTION_URI?: string,
     DEPLOYMENT_NAME: string,
 }
@@ -65,9 +65,68 @@ export const ow: OpWriter = {
     returnStackTop: stat("returnStackTop"),
     returnVariable: creator("returnVariable"),
     returnVoid: stat("returnVoid"),
+    returnCursor: stat("returnCursor"),
+    now: stat("now"),
+    formatTimestamp: creator("formatTimestamp"),
+    parseTimestamp: creator("parseTimestamp"),
+    bytesToString: creator("bytesToString"),
+    stringToBytes: creator("stringToBytes"),
+    toDecimal: stat("toDecimal"),
+    roundDecimal: creator("roundDecimal"),
+    nMod: stat("nMod"),
+    nIntDivide: stat("nIntDivide"),
+    nPow: stat("nPow"),
+    nAbs: stat("nAbs"),
+    nMin: stat("nMin"),
+    nMax: stat("nMax"),
+    nFloor: stat("nFloor"),
+    nCeil: stat("nCeil"),
+    nRound: stat("nRound"),
+    stringLen: stat("stringLen"),
+    stringSlice: stat("stringSlice"),
+    stringIndexOf: stat("stringIndexOf"),
+    stringContains: stat("stringContains"),
+    stringStartsWith: stat("stringStartsWith"),
+    stringEndsWith: stat("stringEndsWith"),
+    stringSplit: stat("stringSplit"),
+    stringTrim: stat("stringTrim"),
+    stringUpper: stat("stringUpper"),
+    stringLower: stat("stringLower"),
+    stringReplace: creator("stringReplace"),
+    regexMatch: creator("regexMatch"),
+    regexCapture: creator("regexCapture"),
+    parseInt: stat("parseInt"),
+    parseDouble: stat("parseDouble"),
+    arraySlice: stat("arraySlice"),
+    arrayConcat: stat("arrayConcat"),
+    arrayReverse: stat("arrayReverse"),
+    arrayContains: stat("arrayContains"),
+    arrayIndexOf: stat("arrayIndexOf"),
+    arraySort: creator("arraySort"),
+    arrayDedup: stat("arrayDedup"),
+    arrayRemove: stat("arrayRemove"),
+    arrayMap: creator("arrayMap"),
+    arrayFilter: creator("arrayFilter"),
+    arrayReduce: creator("arrayReduce"),
+    arrayAny: creator("arrayAny"),
+    arrayAll: creator("arrayAll"),
+    arrayFind: creator("arrayFind"),
+    objectMerge: creator("objectMerge"),
+    objectDelete: stat("objectDelete"),
+    objectHasKey: stat("objectHasKey"),
+    objectValues: stat("objectValues"),
+    objectEntries: stat("objectEntries"),
+    objectPick: stat("objectPick"),
+    objectOmit: stat("objectOmit"),
+    getPath: creator("getPath"),
+    setPath: creator("setPath"),
+    revokeRole: creator("revokeRole"),
+    roleRevoked: creator("roleRevoked"),
     enforceSchemaInstanceOnHeap: creator("enforceSchemaInstanceOnHeap"),
     enforceSchemaOnHeap: creator("enforceSchemaOnHeap"),
+    enforceSchemaOrReject: creator("enforceSchemaOrReject"),
     equal: stat("equal"),
+    notEqual: stat("notEqual"),
     extractFields: creator("extractFields"),
     insertFromHeap: creator("insertFromHeap"),
     insertFromStack: creator("insertFromStack"),
@@ -75,11 +134,16 @@ export const ow: OpWriter = {
     invoke: creator("invoke"),
     moveStackToHeapArray: creator("moveStackToHeapArray"),
     moveStackTopToHeap: stat("moveStackTopToHeap"),
+    openStoreCursor: creator("openStoreCursor"),
     queryStore: creator("queryStore"),
+    queryStorePage: creator("queryStorePage"),
+    aggregateArray: creator("aggregateArray"),
+    aggregateStore: creator("aggregateStore"),
     arrayLen: stat("arrayLen"),
     arrayPush: stat("arrayPush"),
     assertHeapLen: creator("assertHeapLen"),
     assignPreviousToField: creator("assignPreviousToField"),
+    nextInCursor: stat("nextInCursor"),
     nDivide: stat("nDivide"),
     nMinus: stat("nMinus"),
     nMult: stat("nMult"),
@@ -90,6 +154,7 @@ export const ow: OpWriter = {
     updateOne: creator("updateOne"),
     less: stat("less"),
     lesseq: stat("lesseq"),
+    greater: stat("greater"),
     lock: stat("lock"),
     boolAnd: stat("boolAnd"),
     boolOr: stat("boolOr")
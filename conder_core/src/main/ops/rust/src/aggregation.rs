@@ -0,0 +1,396 @@
+use ts_rs::{TS};
+use serde::{Deserialize};
+use std::collections::HashMap;
+use std::cmp::Ordering;
+use rust_decimal::prelude::ToPrimitive;
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+
+use crate::data::{InterpreterType, Obj, Decimal};
+use crate::storage::{SortKey};
+
+#[derive(Deserialize, Clone, TS)]
+#[serde(tag = "kind", content= "data")]
+pub enum Stage {
+    Match(Obj),
+    Group{by: Vec<String>, fields: Vec<GroupField>},
+    Sort(Vec<SortKey>),
+    Limit(u64),
+    Skip(u64),
+    Project(Obj),
+    Lookup{from: String, local_field: String, foreign_field: String, as_field: String}
+}
+
+#[derive(Deserialize, Clone, TS)]
+pub struct GroupField {
+    pub name: String,
+    pub acc: Accumulator
+}
+
+// Store pipelines run in Mongo and local ones run here, and the two differ on sum and avg: Mongo turns an int sum that
+// overflows into a double and mixes decimals with doubles as decimals, where local pipelines report both as errors.
+#[derive(Deserialize, Clone, TS)]
+#[serde(tag = "kind", content= "data")]
+pub enum Accumulator {
+    sum(String),
+    avg(String),
+    min(String),
+    max(String),
+    push(String),
+    first(String),
+    count
+}
+
+pub fn get_path<'a>(value: &'a InterpreterType, path: &str) -> Option<&'a InterpreterType> {
+    let mut target = value;
+    for f in path.split('.') {
+        target = match target {
+            InterpreterType::Object(o) => o.0.get(f)?,
+            _ => return None
+        };
+    }
+    Some(target)
+}
+
+// Missing fields sort with none.
+fn order(left: Option<&InterpreterType>, right: Option<&InterpreterType>) -> Ordering {
+    left.unwrap_or(&InterpreterType::None).total_cmp(right.unwrap_or(&InterpreterType::None))
+}
+
+fn as_double(v: &InterpreterType) -> f64 {
+    match v {
+        InterpreterType::int(i) => *i as f64,
+        InterpreterType::double(d) => *d,
+        InterpreterType::decimal(d) => d.0.to_f64().unwrap_or(0.0),
+        _ => 0.0
+    }
+}
+
+// Ints and decimals sum exactly. Doubles don't have an exact decimal form.
+fn as_decimal(v: &InterpreterType) -> Option<rust_decimal::Decimal> {
+    match v {
+        InterpreterType::int(i) => Some(rust_decimal::Decimal::from(*i)),
+        InterpreterType::decimal(d) => Some(d.0),
+        _ => None
+    }
+}
+
+// Equal numbers of different kinds have to land in the same group bucket, so they hash by their double value.
+fn hash_key<H: Hasher>(key: &InterpreterType, state: &mut H) {
+    match key {
+        InterpreterType::int(_) | InterpreterType::double(_) | InterpreterType::decimal(_) => {
+            let d = as_double(key);
+            state.write_u64(if d == 0.0 {0} else {d.to_bits()});
+        },
+        InterpreterType::Array(a) => a.iter().for_each(|v| hash_key(v, state)),
+        InterpreterType::Object(o) => {
+            let mut sorted_keys: Vec<&String> = o.0.keys().collect();
+            sorted_keys.sort();
+            for k in sorted_keys {
+                state.write(k.as_bytes());
+                hash_key(&o.0[k], state);
+            }
+        },
+        _ => key.hash(state)
+    }
+}
+
+// Stable, so values that tie on every key keep their relative order.
+pub fn sort_by_keys(values: &mut Vec<InterpreterType>, keys: &[SortKey]) {
+    values.sort_by(|l, r| {
+        for k in keys {
+            let o = order(get_path(l, &k.field), get_path(r, &k.field));
+            let o = if k.ascending {o} else {o.reverse()};
+            if o != Ordering::Equal {
+                return o
+            }
+        }
+        Ordering::Equal
+    });
+}
+
+// Keeps the first occurrence of each structurally equal value.
+pub fn dedup(values: Vec<InterpreterType>) -> Vec<InterpreterType> {
+    let mut kept: Vec<InterpreterType> = Vec::with_capacity(values.len());
+    let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+    for v in values {
+        let mut hasher = DefaultHasher::new();
+        hash_key(&v, &mut hasher);
+        let bucket = buckets.entry(hasher.finish()).or_insert(vec![]);
+        if !bucket.iter().any(|i| kept[*i].deep_equals(&v)) {
+            bucket.push(kept.len());
+            kept.push(v);
+        }
+    }
+    kept
+}
+
+pub fn equal(left: Option<&InterpreterType>, right: &InterpreterType) -> bool {
+    match left {
+        Some(l) => l.deep_equals(right),
+        None => match right {
+            InterpreterType::None => true,
+            _ => false
+        }
+    }
+}
+
+fn matches(doc: &InterpreterType, filter: &HashMap<String, InterpreterType>) -> Result<bool, String> {
+    for (path, cond) in filter {
+        let passes = match path.as_str() {
+            "$and" | "$or" => {
+                let clauses = match cond {
+                    InterpreterType::Array(a) => a,
+                    _ => return Err(format!("{} expects an array", path))
+                };
+                let mut results = Vec::with_capacity(clauses.len());
+                for c in clauses {
+                    match c {
+                        InterpreterType::Object(o) => results.push(matches(doc, &o.0)?),
+                        _ => return Err(format!("{} expects an array of objects", path))
+                    };
+                }
+                if path == "$and" {results.iter().all(|r| *r)} else {results.iter().any(|r| *r)}
+            },
+            _ => {
+                let value = get_path(doc, path);
+                match cond {
+                    InterpreterType::Object(o) if o.0.len() > 0 && o.0.keys().all(|k| k.starts_with('$')) => {
+                        let mut all = true;
+                        for (op, arg) in &o.0 {
+                            all = all && match op.as_str() {
+                                "$eq" => equal(value, arg),
+                                "$ne" => !equal(value, arg),
+                                "$gt" => value.is_some() && order(value, Some(arg)) == Ordering::Greater,
+                                "$gte" => value.is_some() && order(value, Some(arg)) != Ordering::Less,
+                                "$lt" => value.is_some() && order(value, Some(arg)) == Ordering::Less,
+                                "$lte" => value.is_some() && order(value, Some(arg)) != Ordering::Greater,
+                                "$in" => match arg {
+                                    InterpreterType::Array(options) => options.iter().any(|o| equal(value, o)),
+                                    _ => return Err("$in expects an array".to_string())
+                                },
+                                "$exists" => match arg {
+                                    InterpreterType::bool(b) => *b == value.is_some(),
+                                    _ => return Err("$exists expects a boolean".to_string())
+                                },
+                                _ => return Err(format!("Unsupported match operator {}", op))
+                            };
+                        }
+                        all
+                    },
+                    _ => equal(value, cond)
+                }
+            }
+        };
+        if !passes {
+            return Ok(false)
+        }
+    }
+    Ok(true)
+}
+
+fn group_key(doc: &InterpreterType, by: &Vec<String>) -> InterpreterType {
+    let get = |path: &String| get_path(doc, path).cloned().unwrap_or(InterpreterType::None);
+    match by.len() {
+        0 => InterpreterType::None,
+        1 => get(&by[0]),
+        _ => InterpreterType::Array(by.iter().map(get).collect())
+    }
+}
+
+// Sums are ints while every value is an int, decimals if any value is a decimal, and doubles otherwise.
+// Overflow is an error rather than falling back to a double, as is summing decimals with doubles.
+fn accumulate(acc: &Accumulator, docs: &Vec<InterpreterType>) -> Result<InterpreterType, String> {
+    let values = |path: &String| {
+        docs.iter().filter_map(|d| match get_path(d, path) {
+            Some(InterpreterType::None) | None => None,
+            Some(v) => Some(v)
+        }).collect::<Vec<&InterpreterType>>()
+    };
+    Ok(match acc {
+        Accumulator::count => InterpreterType::int(docs.len() as i64),
+        Accumulator::sum(path) => {
+            let nums: Vec<&InterpreterType> = values(path).into_iter().filter(|v| match v {
+                InterpreterType::int(_) | InterpreterType::double(_) | InterpreterType::decimal(_) => true,
+                _ => false
+            }).collect();
+            let any_double = nums.iter().any(|v| match v {InterpreterType::double(_) => true, _ => false});
+            let any_decimal = nums.iter().any(|v| match v {InterpreterType::decimal(_) => true, _ => false});
+            if any_decimal && any_double {
+                return Err("cannot sum decimals and doubles together".to_string())
+            }
+            if any_decimal {
+                let mut total = rust_decimal::Decimal::from(0);
+                for v in nums {
+                    total = match as_decimal(v).and_then(|d| total.checked_add(d)) {
+                        Some(t) => t,
+                        None => return Err("decimal overflow in sum".to_string())
+                    };
+                }
+                InterpreterType::decimal(Decimal(total))
+            } else if any_double {
+                InterpreterType::double(nums.iter().map(|v| as_double(v)).sum())
+            } else {
+                let mut total = 0i64;
+                for v in nums {
+                    total = match v {
+                        InterpreterType::int(i) => match total.checked_add(*i) {
+                            Some(t) => t,
+                            None => return Err("integer overflow in sum".to_string())
+                        },
+                        _ => total
+                    };
+                }
+                InterpreterType::int(total)
+            }
+        },
+        // Averages that include a decimal are decimals, like Mongo's $avg. Otherwise they're doubles.
+        Accumulator::avg(path) => {
+            let nums: Vec<&InterpreterType> = values(path).into_iter().filter(|v| match v {
+                InterpreterType::int(_) | InterpreterType::double(_) | InterpreterType::decimal(_) => true,
+                _ => false
+            }).collect();
+            let any_double = nums.iter().any(|v| match v {InterpreterType::double(_) => true, _ => false});
+            let any_decimal = nums.iter().any(|v| match v {InterpreterType::decimal(_) => true, _ => false});
+            if nums.len() == 0 {
+                InterpreterType::None
+            } else if any_decimal && any_double {
+                return Err("cannot average decimals and doubles together".to_string())
+            } else if any_decimal {
+                let mut total = rust_decimal::Decimal::from(0);
+                for v in &nums {
+                    total = match as_decimal(v).and_then(|d| total.checked_add(d)) {
+                        Some(t) => t,
+                        None => return Err("decimal overflow in average".to_string())
+                    };
+                }
+                match total.checked_div(rust_decimal::Decimal::from(nums.len() as i64)) {
+                    Some(avg) => InterpreterType::decimal(Decimal(avg)),
+                    None => return Err("decimal overflow in average".to_string())
+                }
+            } else {
+                InterpreterType::double(nums.iter().map(|v| as_double(v)).sum::<f64>() / nums.len() as f64)
+            }
+        },
+        Accumulator::min(path) => values(path).into_iter().min_by(|l, r| order(Some(*l), Some(*r))).cloned().unwrap_or(InterpreterType::None),
+        Accumulator::max(path) => values(path).into_iter().max_by(|l, r| order(Some(*l), Some(*r))).cloned().unwrap_or(InterpreterType::None),
+        Accumulator::push(path) => InterpreterType::Array(values(path).into_iter().cloned().collect()),
+        Accumulator::first(path) => match docs.first() {
+            Some(d) => get_path(d, path).cloned().unwrap_or(InterpreterType::None),
+            None => InterpreterType::None
+        }
+    })
+}
+
+fn project(doc: InterpreterType, projection: &HashMap<String, InterpreterType>) -> Result<InterpreterType, String> {
+    let mut obj = match doc {
+        InterpreterType::Object(o) => o.0,
+        _ => return Err("Can only project objects".to_string())
+    };
+    let include = |v: &InterpreterType| match v {
+        InterpreterType::bool(b) => *b,
+        InterpreterType::int(i) => *i != 0,
+        _ => true
+    };
+    let inclusive: Vec<&String> = projection.iter().filter(|(_, v)| include(v)).map(|(k, _)| k).collect();
+    if inclusive.len() == 0 {
+        for k in projection.keys() {
+            obj.remove(k);
+        }
+        return Ok(InterpreterType::Object(Obj(obj)))
+    }
+    if inclusive.len() != projection.len() {
+        return Err("Cannot mix inclusion and exclusion in a projection".to_string())
+    }
+    let mut re = HashMap::with_capacity(inclusive.len());
+    for k in inclusive {
+        if let Some(v) = obj.remove(k) {
+            re.insert(k.clone(), v);
+        }
+    }
+    Ok(InterpreterType::Object(Obj(re)))
+}
+
+// Evaluates the pipeline in memory. Lookups are resolved against the given sources rather than stores.
+pub fn evaluate(pipeline: &Vec<Stage>, mut docs: Vec<InterpreterType>, sources: &HashMap<String, InterpreterType>) -> Result<Vec<InterpreterType>, String> {
+    for stage in pipeline {
+        docs = match stage {
+            Stage::Match(filter) => {
+                let mut kept = Vec::with_capacity(docs.len());
+                for d in docs {
+                    if matches(&d, &filter.0)? {
+                        kept.push(d);
+                    }
+                }
+                kept
+            },
+            Stage::Group{by, fields} => {
+                let mut keys: Vec<InterpreterType> = vec![];
+                let mut groups: Vec<Vec<InterpreterType>> = vec![];
+                let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+                for d in docs {
+                    let key = group_key(&d, by);
+                    let mut hasher = DefaultHasher::new();
+                    hash_key(&key, &mut hasher);
+                    let bucket = buckets.entry(hasher.finish()).or_insert(vec![]);
+                    match bucket.iter().find(|i| equal(Some(&keys[**i]), &key)).cloned() {
+                        Some(i) => groups[i].push(d),
+                        None => {
+                            bucket.push(keys.len());
+                            keys.push(key);
+                            groups.push(vec![d]);
+                        }
+                    };
+                }
+                let mut grouped = Vec::with_capacity(keys.len());
+                for (key, members) in keys.into_iter().zip(groups.iter()) {
+                    let mut out = HashMap::with_capacity(fields.len() + 1);
+                    out.insert("_key".to_string(), key);
+                    for f in fields {
+                        out.insert(f.name.clone(), accumulate(&f.acc, members)?);
+                    }
+                    grouped.push(InterpreterType::Object(Obj(out)));
+                }
+                grouped
+            },
+            Stage::Sort(keys) => {
+                sort_by_keys(&mut docs, keys);
+                docs
+            },
+            Stage::Limit(n) => {
+                docs.truncate(*n as usize);
+                docs
+            },
+            Stage::Skip(n) => docs.into_iter().skip(*n as usize).collect(),
+            Stage::Project(projection) => {
+                let mut projected = Vec::with_capacity(docs.len());
+                for d in docs {
+                    projected.push(project(d, &projection.0)?);
+                }
+                projected
+            },
+            Stage::Lookup{from, local_field, foreign_field, as_field} => {
+                let foreign = match sources.get(from) {
+                    Some(InterpreterType::Array(a)) => a,
+                    _ => return Err(format!("No source named {} to look up from", from))
+                };
+                let mut joined = Vec::with_capacity(docs.len());
+                for d in docs {
+                    let local = get_path(&d, local_field).cloned().unwrap_or(InterpreterType::None);
+                    let found: Vec<InterpreterType> = foreign.iter()
+                        .filter(|f| equal(get_path(f, foreign_field), &local))
+                        .cloned()
+                        .collect();
+                    let mut obj = match d {
+                        InterpreterType::Object(o) => o.0,
+                        _ => return Err("Can only look up from objects".to_string())
+                    };
+                    obj.insert(as_field.clone(), InterpreterType::Array(found));
+                    joined.push(InterpreterType::Object(Obj(obj)));
+                }
+                joined
+            }
+        };
+    }
+    Ok(docs)
+}
@@ -4,6 +4,11 @@ use std::fmt::Debug;
 use serde::{Deserialize, Serialize, Serializer, Deserializer};
 use std::any::TypeId;
 use std::hash::{Hash, Hasher};
+use serde::de::Error;
+use chrono::{DateTime, Utc, SecondsFormat};
+use std::str::FromStr;
+use std::cmp::Ordering;
+use rust_decimal::prelude::ToPrimitive;
 
 #[derive(Debug, Clone)]
 pub struct Obj(pub HashMap<String, InterpreterType>);
@@ -39,6 +44,170 @@ impl<'de> Deserialize<'de> for Obj {
     }
 }
 
+// A point in time, sent over the wire as `{"$date": "<RFC 3339>"}`.
+#[derive(Clone, Debug)]
+pub struct Timestamp(pub DateTime<Utc>);
+
+// Raw bytes, sent over the wire as `{"$binary": "<base64>"}`.
+#[derive(Clone, Debug)]
+pub struct Binary(pub Vec<u8>);
+
+// A fixed-point decimal, sent over the wire as `{"$numberDecimal": "<digits>"}` so no precision is lost.
+#[derive(Clone, Debug)]
+pub struct Decimal(pub rust_decimal::Decimal);
+
+// Mongo's Decimal128 holds 34 significant digits but rust_decimal only holds 28.
+// Longer decimals are refused wherever they're read rather than rounded.
+pub const DECIMAL_DIGITS: usize = 28;
+
+// Leading zeros and trailing zeros after the point don't count.
+fn significant_digits(s: &str) -> usize {
+    let mantissa = s.split(|c| c == 'e' || c == 'E').next().unwrap_or("");
+    let mut digits: String = mantissa.chars().filter(|c| c.is_ascii_digit()).collect();
+    if mantissa.contains('.') {
+        digits = digits.trim_end_matches('0').to_string();
+    }
+    digits.trim_start_matches('0').len()
+}
+
+impl Decimal {
+    // Accepts plain and scientific notation.
+    pub fn parse(s: &str) -> Result<Decimal, String> {
+        let digits = significant_digits(s);
+        if digits > DECIMAL_DIGITS {
+            return Err(format!("Invalid decimal {}: has {} significant digits but at most {} are supported", s, digits, DECIMAL_DIGITS))
+        }
+        match rust_decimal::Decimal::from_str(s) {
+            Ok(d) => Ok(Decimal(d)),
+            Err(_) => match rust_decimal::Decimal::from_scientific(s) {
+                Ok(d) => Ok(Decimal(d)),
+                Err(e) => Err(format!("Invalid decimal {}: {}", s, e))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DecimalWire {
+    #[serde(rename = "$numberDecimal")]
+    decimal: String
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DateWire {
+    #[serde(rename = "$date")]
+    date: String
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BinaryWire {
+    #[serde(rename = "$binary")]
+    binary: String
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        DateWire {date: self.0.to_rfc3339_opts(SecondsFormat::Millis, true)}.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D>(deserializer: D) ->  Result<Self, D::Error> where D: Deserializer<'de>{
+        let wire = DateWire::deserialize(deserializer)?;
+        match DateTime::parse_from_rfc3339(&wire.date) {
+            Ok(d) => Ok(Timestamp(d.with_timezone(&Utc))),
+            Err(e) => Err(D::Error::custom(format!("Invalid timestamp {}: {}", wire.date, e)))
+        }
+    }
+}
+
+impl Serialize for Binary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        BinaryWire {binary: base64::encode(&self.0)}.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Binary {
+    fn deserialize<D>(deserializer: D) ->  Result<Self, D::Error> where D: Deserializer<'de>{
+        let wire = BinaryWire::deserialize(deserializer)?;
+        match base64::decode(&wire.binary) {
+            Ok(b) => Ok(Binary(b)),
+            Err(e) => Err(D::Error::custom(format!("Invalid base64: {}", e)))
+        }
+    }
+}
+
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        DecimalWire {decimal: self.0.to_string()}.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D>(deserializer: D) ->  Result<Self, D::Error> where D: Deserializer<'de>{
+        let wire = DecimalWire::deserialize(deserializer)?;
+        Decimal::parse(&wire.decimal).map_err(D::Error::custom)
+    }
+}
+
+impl TS for Decimal {
+    fn name() -> String {
+        return "{$numberDecimal: string}".to_string();
+    }
+
+    fn dependencies() -> Vec<(TypeId, String)>{
+        return vec![]
+    }
+
+    fn transparent() -> bool {
+        return false
+    }
+
+    fn inline(indent: usize) -> String {
+        return "{$numberDecimal: string}".to_string();
+    }
+}
+
+impl TS for Timestamp {
+    fn name() -> String {
+        return "{$date: string}".to_string();
+    }
+
+    fn dependencies() -> Vec<(TypeId, String)>{
+        return vec![]
+    }
+
+    fn transparent() -> bool {
+        return false
+    }
+
+    fn inline(indent: usize) -> String {
+        return "{$date: string}".to_string();
+    }
+}
+
+impl TS for Binary {
+    fn name() -> String {
+        return "{$binary: string}".to_string();
+    }
+
+    fn dependencies() -> Vec<(TypeId, String)>{
+        return vec![]
+    }
+
+    fn transparent() -> bool {
+        return false
+    }
+
+    fn inline(indent: usize) -> String {
+        return "{$binary: string}".to_string();
+    }
+}
+
+// Decimals, timestamps and bytes must come before objects so their wire forms aren't read as plain objects.
 #[derive(Serialize, Deserialize, Clone, Debug, TS)]
 #[serde(untagged)]
 pub enum InterpreterType {
@@ -46,11 +215,101 @@ pub enum InterpreterType {
     double(f64),
     bool(bool),
     string(String),
+    decimal(Decimal),
+    timestamp(Timestamp),
+    bytes(Binary),
     Array(Vec<InterpreterType>),
     Object(Obj),
     None
 } 
 
+impl InterpreterType {
+    // Kinds sort the way the storage layer sorts them: none, numbers, strings, objects, arrays, bytes, bools, then timestamps.
+    fn rank(&self) -> u8 {
+        match self {
+            InterpreterType::None => 0,
+            InterpreterType::int(_) | InterpreterType::double(_) | InterpreterType::decimal(_) => 1,
+            InterpreterType::string(_) => 2,
+            InterpreterType::Object(_) => 3,
+            InterpreterType::Array(_) => 4,
+            InterpreterType::bytes(_) => 5,
+            InterpreterType::bool(_) => 6,
+            InterpreterType::timestamp(_) => 7
+        }
+    }
+
+    pub fn total_cmp(&self, other: &InterpreterType) -> Ordering {
+        match (self, other) {
+            (InterpreterType::int(l), InterpreterType::int(r)) => l.cmp(r),
+            (InterpreterType::int(l), InterpreterType::decimal(r)) => rust_decimal::Decimal::from(*l).cmp(&r.0),
+            (InterpreterType::decimal(l), InterpreterType::int(r)) => l.0.cmp(&rust_decimal::Decimal::from(*r)),
+            (InterpreterType::decimal(l), InterpreterType::decimal(r)) => l.0.cmp(&r.0),
+            (InterpreterType::int(l), InterpreterType::double(r)) => cmp_int_double(*l, *r),
+            (InterpreterType::double(l), InterpreterType::int(r)) => cmp_int_double(*r, *l).reverse(),
+            (InterpreterType::double(l), InterpreterType::double(r)) => cmp_doubles(*l, *r),
+            (InterpreterType::decimal(l), InterpreterType::double(r)) => cmp_doubles(l.0.to_f64().unwrap_or(std::f64::NAN), *r),
+            (InterpreterType::double(l), InterpreterType::decimal(r)) => cmp_doubles(*l, r.0.to_f64().unwrap_or(std::f64::NAN)),
+            (InterpreterType::string(l), InterpreterType::string(r)) => l.cmp(r),
+            (InterpreterType::bool(l), InterpreterType::bool(r)) => l.cmp(r),
+            (InterpreterType::bytes(l), InterpreterType::bytes(r)) => l.0.cmp(&r.0),
+            (InterpreterType::timestamp(l), InterpreterType::timestamp(r)) => l.0.cmp(&r.0),
+            (InterpreterType::Array(l), InterpreterType::Array(r)) => {
+                for (a, b) in l.iter().zip(r.iter()) {
+                    let o = a.total_cmp(b);
+                    if o != Ordering::Equal {
+                        return o
+                    }
+                }
+                l.len().cmp(&r.len())
+            },
+            // Objects compare field by field in key order.
+            (InterpreterType::Object(l), InterpreterType::Object(r)) => {
+                let mut left: Vec<(&String, &InterpreterType)> = l.0.iter().collect();
+                let mut right: Vec<(&String, &InterpreterType)> = r.0.iter().collect();
+                left.sort_by(|a, b| a.0.cmp(b.0));
+                right.sort_by(|a, b| a.0.cmp(b.0));
+                for ((lk, lv), (rk, rv)) in left.iter().zip(right.iter()) {
+                    let o = lk.cmp(rk).then_with(|| lv.total_cmp(rv));
+                    if o != Ordering::Equal {
+                        return o
+                    }
+                }
+                left.len().cmp(&right.len())
+            },
+            _ => self.rank().cmp(&other.rank())
+        }
+    }
+
+    pub fn deep_equals(&self, other: &InterpreterType) -> bool {
+        self.total_cmp(other) == Ordering::Equal
+    }
+}
+
+// NaN sorts below every other number and equals itself.
+fn cmp_doubles(l: f64, r: f64) -> Ordering {
+    match (l.is_nan(), r.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => l.partial_cmp(&r).unwrap_or(Ordering::Equal)
+    }
+}
+
+// Compares without rounding the int to a double, which loses precision past 2^53.
+fn cmp_int_double(i: i64, d: f64) -> Ordering {
+    if d.is_nan() {
+        return Ordering::Greater
+    }
+    if d >= 9223372036854775808.0 {
+        return Ordering::Less
+    }
+    if d < -9223372036854775808.0 {
+        return Ordering::Greater
+    }
+    let whole = d.trunc();
+    i.cmp(&(whole as i64)).then_with(|| 0f64.partial_cmp(&(d - whole)).unwrap_or(Ordering::Equal))
+}
+
 impl Hash for InterpreterType {
     fn hash<H: Hasher>(&self, state: &mut H) {
         match self {
@@ -80,6 +339,18 @@ impl Hash for InterpreterType {
                     entry.hash(state);
                 }
             },
+            InterpreterType::decimal(d) => {
+                state.write(b"n");
+                state.write(d.0.normalize().to_string().as_bytes());
+            },
+            InterpreterType::timestamp(t) => {
+                state.write(b"t");
+                state.write_i64(t.0.timestamp_millis());
+            },
+            InterpreterType::bytes(b) => {
+                state.write(b"b");
+                state.write(&b.0);
+            },
             InterpreterType::None => {
             }
         };
@@ -1,11 +1,16 @@
 use std::collections::HashMap;
-use futures::future::{BoxFuture, FutureExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::stream::{self, StreamExt};
 
 use crate::data::{InterpreterType, Obj};
-use crate::ops::{Op};
+use crate::ops::{Op, Procedure};
 use crate::schemas::Schema;
 use crate::locks;
-use actix_web::{Responder, HttpResponse};
+use crate::storage::{QueryStream, Store, Validator};
+use crate::roles::{Keyring, Verifier};
+use actix_web::{Responder, HttpResponse, error};
+use bytes::Bytes;
 
 
 pub struct Execution<'a> {
@@ -17,13 +22,29 @@ pub struct Context<'a> {
     pub heap: Vec<InterpreterType>,
     pub stack: Vec<InterpreterType>,
     pub locks: HashMap<String, locks::Mutex>,
+    pub cursors: Vec<Option<QueryStream>>,
     pub exec: Execution<'a>,
 }
 
 
 pub enum ContextState {
     Continue,
-    Done(InterpreterType)
+    Done(InterpreterType),
+    Stream(QueryStream),
+    Reject(InterpreterType)
+}
+
+pub enum Output {
+    Value(InterpreterType),
+    Stream(QueryStream),
+    // The request was refused and the value explains why to the client.
+    Rejected(InterpreterType)
+}
+
+#[derive(Clone, Copy)]
+pub enum ResponseFormat {
+    Json,
+    NdJson
 }
 
 impl <'a> Context<'a>  {
@@ -66,7 +87,8 @@ impl <'a> Context<'a>  {
                 next_op_index: 0
             },
             heap: heap,
-            locks: HashMap::new()
+            locks: HashMap::new(),
+            cursors: vec![]
         }
     }
 }
@@ -74,32 +96,89 @@ impl <'a> Context<'a>  {
 pub struct Globals<'a> {
     pub schemas: &'a HashMap<String, Schema>, 
     pub db: Option<&'a mongodb::Database>, 
-    pub stores: &'a HashMap<String, Schema>,
-    pub fns: &'a HashMap<String, Vec<Op>>,
+    pub stores: &'a HashMap<String, Store>,
+    pub fns: &'a HashMap<String, Procedure>,
     pub lm: Option<&'a etcd_rs::Client>,
-    pub private_key: &'a[u8; 64],
-    pub public_key: &'a [u8; 32]
+    pub keys: &'a Keyring,
+    pub deployment: &'a str,
+    // How many procedure calls and callbacks deep the current op is.
+    pub depth: usize,
+    // How many ops the request has run, counted across every call and callback it makes.
+    pub ops_run: &'a AtomicU64
 }
 
+// Procedures and callbacks can call each other, so nesting is limited to stop runaway recursion
+// before it exhausts memory.
+pub const MAX_CALL_DEPTH: usize = 64;
+// Loops, including ones inside callbacks, could otherwise run forever, so a request can only run so many ops.
+pub const MAX_OPS: u64 = 1_000_000;
+
+impl<'a> Globals<'a> {
+    // The globals for a call made from the current op.
+    pub fn nested(&self) -> Result<Globals<'a>, String> {
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(format!("Calls nested more than {} deep", MAX_CALL_DEPTH))
+        }
+        Ok(Globals {depth: self.depth + 1, ..*self})
+    }
+
+    // Counts an op against the request's budget.
+    pub fn charge_op(&self) -> Result<(), String> {
+        if self.ops_run.fetch_add(1, Ordering::Relaxed) >= MAX_OPS {
+            return Err(format!("Request ran more than {} ops", MAX_OPS))
+        }
+        Ok(())
+    }
+
+    pub fn validator<'b>(&'b self, store: &'b str) -> Result<Validator<'b>, String> {
+        match self.stores.get(store) {
+            Some(s) => Ok(Validator {
+                store: store,
+                schema: &s.schema,
+                schemas: self.schemas,
+                verifier: self.verifier()
+            }),
+            None => Err(format!("Store {} does not exist", store))
+        }
+    }
+
+    pub fn verifier(&self) -> Verifier<'a> {
+        Verifier {
+            keys: self.keys,
+            audience: self.deployment
+        }
+    }
+}
 
 pub fn conduit_byte_code_interpreter_internal<'a>(
     mut current: Context<'a>,
     globals: &'a Globals<'a>
-) ->BoxFuture<'a, Result<InterpreterType, String>> {
+) ->BoxFuture<'a, Result<Output, String>> {
     
     if current.exec.ops.len() == 0 {
-        return async {Ok(InterpreterType::None)}.boxed();
+        return async {Ok(Output::Value(InterpreterType::None))}.boxed();
     }
     
     return async move {
         loop {
-            let res: Result<ContextState, String> = current.execute_next_op(globals).await;
+            let res: Result<ContextState, String> = match globals.charge_op() {
+                Ok(()) => current.execute_next_op(globals).await,
+                Err(e) => Err(e)
+            };
 
             let state = match res {
                 Ok(body) => match body {
                     ContextState::Done(data) => {
                         current.release_all_locks(&globals).await;
-                        return Ok(data);
+                        return Ok(Output::Value(data));
+                    },
+                    ContextState::Stream(s) => {
+                        current.release_all_locks(&globals).await;
+                        return Ok(Output::Stream(s));
+                    },
+                    ContextState::Reject(reason) => {
+                        current.release_all_locks(&globals).await;
+                        return Ok(Output::Rejected(reason));
                     },
                     _ => {} // The ops are responsible for getting the next instruction.
                 },            
@@ -115,17 +194,135 @@ pub fn conduit_byte_code_interpreter_internal<'a>(
       
 }
 
+// Checks arguments against the parameters a procedure declares, returning them with strip objects sanitized.
+fn check_arguments(procedure: &Procedure, mut args: Vec<InterpreterType>, globals: &Globals<'_>) -> Result<Vec<InterpreterType>, Vec<String>> {
+    let params = match &procedure.params {
+        Some(params) => params,
+        None => return Ok(args)
+    };
+    if params.len() != args.len() {
+        return Err(vec![format!("expected {} arguments, got {}", params.len(), args.len())])
+    }
+    let mut violations: Vec<String> = vec![];
+    for (i, schema) in params.iter().enumerate() {
+        match schema.sanitize(&args[i], globals.schemas, &globals.verifier()) {
+            Ok(sanitized) => args[i] = sanitized,
+            Err(found) => violations.extend(found.iter().map(|v| format!("argument {}: {}", i, v)))
+        };
+    }
+    if violations.len() > 0 {
+        return Err(violations)
+    }
+    Ok(args)
+}
+
+// Runs a procedure whose arguments have already been checked, then checks its return value.
+// A bad return value is a fault in the procedure.
+async fn run_procedure<'a>(
+    name: &str,
+    procedure: &'a Procedure,
+    args: Vec<InterpreterType>,
+    globals: &'a Globals<'a>) -> Result<Output, String> {
+    let output = conduit_byte_code_interpreter_internal(Context::new(&procedure.ops, args), globals).await?;
+    match (output, &procedure.returns) {
+        (Output::Value(v), Some(schema)) => match schema.sanitize(&v, globals.schemas, &globals.verifier()) {
+            Ok(sanitized) => Ok(Output::Value(sanitized)),
+            Err(violations) => Err(format!(
+                "Procedure {} returned a value that does not match its return type: {}",
+                name,
+                violations.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(", ")
+            ))
+        },
+        (output, _) => Ok(output)
+    }
+}
+
+// Runs a procedure called by another one. Passing it bad arguments is a fault in the caller rather than
+// something to tell the client about, so it's an error.
+pub async fn execute_procedure<'a>(
+    name: &str,
+    procedure: &'a Procedure,
+    args: Vec<InterpreterType>,
+    globals: &'a Globals<'a>) -> Result<Output, String> {
+    match check_arguments(procedure, args, globals) {
+        Ok(args) => run_procedure(name, procedure, args, globals).await,
+        Err(violations) => Err(format!("Procedure {} was called with invalid arguments: {}", name, violations.join(", ")))
+    }
+}
+
+// Requests with arguments that don't match the procedure's parameters are rejected back to the client.
 pub async fn conduit_byte_code_interpreter(
+    name: &str,
     state: Vec<InterpreterType>, 
-    ops: &Vec<Op>,
-    globals: Globals<'_>) -> impl Responder {
-    let context = Context::new(ops, state);
-    let output = conduit_byte_code_interpreter_internal(context, &globals).await;
+    procedure: &Procedure,
+    globals: Globals<'_>,
+    format: ResponseFormat) -> impl Responder {
+    let args = match check_arguments(procedure, state, &globals) {
+        Ok(args) => args,
+        Err(violations) => {
+            let mut reason = HashMap::with_capacity(3);
+            reason.insert("error".to_string(), InterpreterType::string("invalid arguments".to_string()));
+            reason.insert("procedure".to_string(), InterpreterType::string(name.to_string()));
+            reason.insert("violations".to_string(), InterpreterType::Array(
+                violations.into_iter().map(InterpreterType::string).collect()
+            ));
+            return HttpResponse::BadRequest().json(InterpreterType::Object(Obj(reason)))
+        }
+    };
+    let output = run_procedure(name, procedure, args, &globals).await;
     return match output {
-        Ok(data) => HttpResponse::Ok().json(data),
+        Ok(Output::Value(data)) => HttpResponse::Ok().json(data),
+        Ok(Output::Stream(s)) => stream_response(s, format).await,
+        Ok(Output::Rejected(reason)) => HttpResponse::BadRequest().json(reason),
         Err(s) => {
             eprintln!("{}", s);
             HttpResponse::BadRequest().finish()
         }
     }
-}
\ No newline at end of file
+}
+
+// The status is sent before most of a stream has been read. The first entry is read up front so a query that fails
+// immediately still gets an error status. A failure after that ends the output with an `{"$error": ...}` entry, as the
+// last line of NDJSON or the last element of the array, so clients can tell a truncated result from a complete one.
+async fn stream_response(mut s: QueryStream, format: ResponseFormat) -> HttpResponse {
+    let first = match s.next().await {
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().finish()
+        },
+        first => first
+    };
+    let entries = stream::iter(first).chain(s).scan(false, |failed, v| future::ready(match v {
+        _ if *failed => None,
+        Ok(data) => Some(data),
+        Err(e) => {
+            eprintln!("{}", e);
+            *failed = true;
+            let mut marker = HashMap::with_capacity(1);
+            marker.insert("$error".to_string(), InterpreterType::string("stream interrupted".to_string()));
+            Some(InterpreterType::Object(Obj(marker)))
+        }
+    })).enumerate().map(move |(i, data)| {
+        let mut bytes = match format {
+            ResponseFormat::NdJson => vec![],
+            ResponseFormat::Json if i == 0 => vec![],
+            ResponseFormat::Json => vec![b',']
+        };
+        match serde_json::to_writer(&mut bytes, &data) {
+            Ok(_) => {},
+            Err(e) => return Err(error::ErrorInternalServerError(format!("Failure serializing: {}", e)))
+        };
+        if let ResponseFormat::NdJson = format {
+            bytes.push(b'\n');
+        }
+        Ok(Bytes::from(bytes))
+    });
+    match format {
+        ResponseFormat::NdJson => HttpResponse::Ok().content_type("application/x-ndjson").streaming(entries),
+        ResponseFormat::Json => {
+            let open = stream::iter(vec![Ok(Bytes::from_static(b"["))]);
+            let close = stream::iter(vec![Ok(Bytes::from_static(b"]"))]);
+            HttpResponse::Ok().content_type("application/json").streaming(open.chain(entries).chain(close))
+        }
+    }
+}
@@ -0,0 +1,360 @@
+// Conversion between schemas and JSON Schema (draft 2020-12).
+//
+// Three extension keywords keep the conversion lossless:
+// - `x-conder-role: <name>` marks a role. Roles are objects with a `_name`, an `_sig` array of bytes,
+//   an `_sigv` signature version, the `_kid` of the signing key, an optional `_state` and the signed
+//   claims `_iat`, `_exp`, `_aud` and `_jti`; the signature can only be checked by the kernel.
+// - `x-conder-policy: closed | open | strip` records the policy of objects that declare one.
+//   Validators that don't know it treat strip objects as open.
+// - `x-conder-type: decimal | timestamp | bytes` marks the wire forms `{"$numberDecimal": ...}`,
+//   `{"$date": ...}` and `{"$binary": ...}`. Decimals also accept integers.
+use std::collections::HashMap;
+use serde_json::{json, Map, Value};
+
+use crate::data::{InterpreterType};
+use crate::schemas::{Schema, ObjSchema, ObjectPolicy, Constraint, Pattern};
+
+pub const DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+pub const DEFS_PREFIX: &str = "#/$defs/";
+
+// Translates a schema into JSON Schema.
+// Type aliases become references to `{ref_prefix}{name}`, so the caller decides where definitions live.
+pub fn export(schema: &Schema, ref_prefix: &str) -> Value {
+    match schema {
+        Schema::Object(fields) => object(fields, &ObjectPolicy::closed, ref_prefix),
+        Schema::PolicyObject(fields, policy) => {
+            let mut obj = object(fields, policy, ref_prefix);
+            obj["x-conder-policy"] = json!(policy_name(policy));
+            obj
+        },
+        Schema::Role(name, state) => json!({
+            "x-conder-role": name,
+            "type": "object",
+            "properties": {
+                "_name": {"type": "string"},
+                "_sig": {"type": "array", "items": {"type": "integer", "minimum": 0, "maximum": 255}},
+                "_sigv": {"type": "integer"},
+                "_iat": export(&Schema::timestamp, ref_prefix),
+                "_exp": export(&Schema::timestamp, ref_prefix),
+                "_aud": {"type": "string"},
+                "_jti": {"type": "string"},
+                "_kid": {"type": "string"},
+                "_state": export(&state[0], ref_prefix)
+            },
+            "required": ["_name", "_sig"],
+            "additionalProperties": false
+        }),
+        Schema::Array(inner) => json!({"type": "array", "items": export(&inner[0], ref_prefix)}),
+        Schema::Union(options) => json!({"anyOf": options.iter().map(|o| export(o, ref_prefix)).collect::<Vec<Value>>()}),
+        Schema::Map(inner) => json!({"type": "object", "additionalProperties": export(&inner[0], ref_prefix)}),
+        Schema::Refined(inner, constraints) => {
+            let mut refined = match export(&inner[0], ref_prefix) {
+                Value::Object(m) => m,
+                other => {
+                    let mut m = Map::new();
+                    m.insert("allOf".to_string(), json!([other]));
+                    m
+                }
+            };
+            for c in constraints {
+                let (keyword, value) = constraint(c);
+                refined.insert(keyword.to_string(), value);
+            }
+            Value::Object(refined)
+        },
+        Schema::TypeAlias(name) => json!({"$ref": format!("{}{}", ref_prefix, name)}),
+        Schema::double => json!({"type": "number"}),
+        Schema::int => json!({"type": "integer"}),
+        Schema::string => json!({"type": "string"}),
+        Schema::bool => json!({"type": "boolean"}),
+        Schema::decimal => json!({
+            "x-conder-type": "decimal",
+            "anyOf": [
+                {"type": "integer"},
+                {
+                    "type": "object",
+                    "properties": {"$numberDecimal": {"type": "string", "pattern": "^-?[0-9]*\\.?[0-9]+([eE][-+]?[0-9]+)?$"}},
+                    "required": ["$numberDecimal"],
+                    "additionalProperties": false
+                }
+            ]
+        }),
+        Schema::timestamp => json!({
+            "x-conder-type": "timestamp",
+            "type": "object",
+            "properties": {"$date": {"type": "string", "format": "date-time"}},
+            "required": ["$date"],
+            "additionalProperties": false
+        }),
+        Schema::bytes => json!({
+            "x-conder-type": "bytes",
+            "type": "object",
+            "properties": {"$binary": {"type": "string", "contentEncoding": "base64"}},
+            "required": ["$binary"],
+            "additionalProperties": false
+        }),
+        Schema::Any => json!({}),
+        Schema::none => json!({"type": "null"})
+    }
+}
+
+// Exports every named schema, keyed by name.
+pub fn export_all(schemas: &HashMap<String, Schema>, ref_prefix: &str) -> Map<String, Value> {
+    schemas.iter().map(|(name, s)| (name.clone(), export(s, ref_prefix))).collect()
+}
+
+fn object(fields: &ObjSchema, policy: &ObjectPolicy, ref_prefix: &str) -> Value {
+    let mut properties = Map::new();
+    let mut required: Vec<&String> = vec![];
+    for (k, v) in &fields.0 {
+        properties.insert(k.clone(), export(v, ref_prefix));
+        if !v.is_optional() {
+            required.push(k);
+        }
+    }
+    required.sort();
+    let mut obj = json!({"type": "object", "properties": properties, "required": required});
+    if let ObjectPolicy::closed = policy {
+        obj["additionalProperties"] = Value::Bool(false);
+    }
+    obj
+}
+
+fn constraint(c: &Constraint) -> (&'static str, Value) {
+    match c {
+        Constraint::min(m) => ("minimum", json!(m)),
+        Constraint::max(m) => ("maximum", json!(m)),
+        Constraint::minLength(l) => ("minLength", json!(l)),
+        Constraint::maxLength(l) => ("maxLength", json!(l)),
+        Constraint::pattern(p) => ("pattern", json!(p)),
+        Constraint::oneOf(options) => ("enum", json!(options)),
+        Constraint::minItems(l) => ("minItems", json!(l)),
+        Constraint::maxItems(l) => ("maxItems", json!(l)),
+        Constraint::uniqueItems => ("uniqueItems", json!(true))
+    }
+}
+
+// Exports named schemas as a standalone JSON Schema document with the schemas under `$defs`.
+pub fn export_document(schemas: &HashMap<String, Schema>) -> Value {
+    json!({"$schema": DIALECT, "$defs": export_all(schemas, DEFS_PREFIX)})
+}
+
+// Reads the named schemas from the `$defs` (or legacy `definitions`) of a JSON Schema document.
+pub fn import_document(doc: &Value) -> Result<HashMap<String, Schema>, String> {
+    let defs = match doc.get("$defs").or(doc.get("definitions")) {
+        Some(Value::Object(d)) => d,
+        Some(_) => return Err("$defs must be an object".to_string()),
+        None => return Err("Document has no $defs".to_string())
+    };
+    let mut schemas = HashMap::with_capacity(defs.len());
+    for (name, def) in defs {
+        match import(def) {
+            Ok(s) => schemas.insert(name.clone(), s),
+            Err(e) => return Err(format!("{}: {}", name, e))
+        };
+    }
+    Ok(schemas)
+}
+
+// Translates a JSON Schema into a schema. References resolve to type aliases named by their last segment.
+pub fn import(value: &Value) -> Result<Schema, String> {
+    let obj = match value {
+        Value::Bool(true) => return Ok(Schema::Any),
+        Value::Object(o) => o,
+        _ => return Err(format!("Unsupported JSON schema {}", value))
+    };
+    for keyword in &["not", "if", "then", "else", "exclusiveMinimum", "exclusiveMaximum", "multipleOf", "patternProperties", "prefixItems", "dependentSchemas"] {
+        if obj.contains_key(*keyword) {
+            return Err(format!("Unsupported JSON schema keyword {}", keyword))
+        }
+    }
+    let base = if let Some(t) = obj.get("x-conder-type") {
+        match t.as_str() {
+            Some("decimal") => Schema::decimal,
+            Some("timestamp") => Schema::timestamp,
+            Some("bytes") => Schema::bytes,
+            _ => return Err(format!("Unknown x-conder-type {}", t))
+        }
+    } else if let Some(role) = obj.get("x-conder-role") {
+        let name = match role {
+            Value::String(n) => n.clone(),
+            _ => return Err("x-conder-role must be a string".to_string())
+        };
+        let state = match obj.get("properties").and_then(|p| p.get("_state")) {
+            Some(s) => import(s)?,
+            None => Schema::Any
+        };
+        return Ok(Schema::Role(name, vec![state]))
+    } else if let Some(r) = obj.get("$ref") {
+        match r {
+            Value::String(path) => Schema::TypeAlias(path.rsplit('/').next().unwrap_or(path).to_string()),
+            _ => return Err("$ref must be a string".to_string())
+        }
+    } else if let Some(options) = obj.get("anyOf").or(obj.get("oneOf")) {
+        Schema::Union(import_all(options)?)
+    } else if let Some(all) = obj.get("allOf") {
+        let mut parts = import_all(all)?;
+        if parts.len() != 1 {
+            return Err("allOf is only supported with a single schema".to_string())
+        }
+        parts.remove(0)
+    } else {
+        match obj.get("type") {
+            None => Schema::Any,
+            Some(Value::String(t)) => typed(t, obj)?,
+            Some(Value::Array(types)) => {
+                let mut options = Vec::with_capacity(types.len());
+                for t in types {
+                    match t {
+                        Value::String(t) => options.push(typed(t, obj)?),
+                        _ => return Err("type must be a string or an array of strings".to_string())
+                    };
+                }
+                Schema::Union(options)
+            },
+            Some(_) => return Err("type must be a string or an array of strings".to_string())
+        }
+    };
+    let constraints = import_constraints(obj)?;
+    if constraints.len() == 0 {
+        Ok(base)
+    } else {
+        Ok(Schema::Refined(vec![base], constraints))
+    }
+}
+
+fn import_all(options: &Value) -> Result<Vec<Schema>, String> {
+    match options {
+        Value::Array(a) => {
+            let mut schemas = Vec::with_capacity(a.len());
+            for o in a {
+                schemas.push(import(o)?);
+            }
+            Ok(schemas)
+        },
+        _ => Err("Expected an array of schemas".to_string())
+    }
+}
+
+fn typed(t: &str, obj: &Map<String, Value>) -> Result<Schema, String> {
+    Ok(match t {
+        "integer" => Schema::int,
+        "number" => Schema::double,
+        "string" => Schema::string,
+        "boolean" => Schema::bool,
+        "null" => Schema::none,
+        "array" => Schema::Array(vec![match obj.get("items") {
+            Some(items) => import(items)?,
+            None => Schema::Any
+        }]),
+        "object" => import_object(obj)?,
+        _ => return Err(format!("Unsupported type {}", t))
+    })
+}
+
+fn import_object(obj: &Map<String, Value>) -> Result<Schema, String> {
+    let policy = match obj.get("x-conder-policy") {
+        Some(Value::String(p)) => Some(match p.as_str() {
+            "closed" => ObjectPolicy::closed,
+            "open" => ObjectPolicy::open,
+            "strip" => ObjectPolicy::strip,
+            _ => return Err(format!("Unknown object policy {}", p))
+        }),
+        Some(_) => return Err("x-conder-policy must be a string".to_string()),
+        None => None
+    };
+    let additional = obj.get("additionalProperties");
+    let properties = match obj.get("properties") {
+        Some(Value::Object(p)) => p,
+        Some(_) => return Err("properties must be an object".to_string()),
+        None => match (policy, additional) {
+            (Some(p), _) => return Ok(Schema::PolicyObject(ObjSchema(HashMap::new()), p)),
+            (None, Some(Value::Bool(false))) => return Ok(Schema::Object(ObjSchema(HashMap::new()))),
+            (None, Some(Value::Object(_))) => return Ok(Schema::Map(vec![import(additional.unwrap())?])),
+            (None, _) => return Ok(Schema::Map(vec![Schema::Any]))
+        }
+    };
+    let required: Vec<&str> = match obj.get("required") {
+        Some(Value::Array(r)) => r.iter().filter_map(|v| v.as_str()).collect(),
+        _ => vec![]
+    };
+    let mut fields = HashMap::with_capacity(properties.len());
+    for (k, v) in properties {
+        let mut field = import(v)?;
+        // Fields that may be missing have to accept none.
+        if !required.contains(&k.as_str()) && !field.is_optional() {
+            field = Schema::Union(vec![field, Schema::none]);
+        }
+        fields.insert(k.clone(), field);
+    }
+    Ok(match (policy, additional) {
+        (Some(p), _) => Schema::PolicyObject(ObjSchema(fields), p),
+        (None, Some(Value::Bool(false))) => Schema::Object(ObjSchema(fields)),
+        (None, None) | (None, Some(Value::Bool(true))) => Schema::PolicyObject(ObjSchema(fields), ObjectPolicy::open),
+        (None, Some(_)) => return Err("Objects with both properties and typed additionalProperties are not supported".to_string())
+    })
+}
+
+fn import_constraints(obj: &Map<String, Value>) -> Result<Vec<Constraint>, String> {
+    let number = |k: &str| match obj.get(k) {
+        Some(v) => match v.as_f64() {
+            Some(n) => Ok(Some(n)),
+            None => Err(format!("{} must be a number", k))
+        },
+        None => Ok(None)
+    };
+    let count = |k: &str| match obj.get(k) {
+        Some(v) => match v.as_u64() {
+            Some(n) => Ok(Some(n)),
+            None => Err(format!("{} must be a non-negative integer", k))
+        },
+        None => Ok(None)
+    };
+    let mut constraints = vec![];
+    if let Some(n) = number("minimum")? {
+        constraints.push(Constraint::min(n));
+    }
+    if let Some(n) = number("maximum")? {
+        constraints.push(Constraint::max(n));
+    }
+    if let Some(n) = count("minLength")? {
+        constraints.push(Constraint::minLength(n));
+    }
+    if let Some(n) = count("maxLength")? {
+        constraints.push(Constraint::maxLength(n));
+    }
+    match obj.get("pattern") {
+        Some(Value::String(p)) => constraints.push(Constraint::pattern(Pattern::new(p)?)),
+        Some(_) => return Err("pattern must be a string".to_string()),
+        None => {}
+    };
+    let options = match (obj.get("enum"), obj.get("const")) {
+        (Some(e), _) => Some(e.clone()),
+        (None, Some(c)) => Some(Value::Array(vec![c.clone()])),
+        (None, None) => None
+    };
+    if let Some(o) = options {
+        match serde_json::from_value::<Vec<InterpreterType>>(o) {
+            Ok(values) => constraints.push(Constraint::oneOf(values)),
+            Err(e) => return Err(format!("Unsupported enum: {}", e))
+        };
+    }
+    if let Some(n) = count("minItems")? {
+        constraints.push(Constraint::minItems(n));
+    }
+    if let Some(n) = count("maxItems")? {
+        constraints.push(Constraint::maxItems(n));
+    }
+    if let Some(Value::Bool(true)) = obj.get("uniqueItems") {
+        constraints.push(Constraint::uniqueItems);
+    }
+    Ok(constraints)
+}
+
+fn policy_name(policy: &ObjectPolicy) -> &'static str {
+    match policy {
+        ObjectPolicy::closed => "closed",
+        ObjectPolicy::open => "open",
+        ObjectPolicy::strip => "strip"
+    }
+}
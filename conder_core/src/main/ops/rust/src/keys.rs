@@ -0,0 +1,91 @@
+// Reading Ed25519 keys from configuration.
+//
+// Keys can be written as:
+// - PEM: PKCS#8 (`BEGIN PRIVATE KEY`) for private keys and SubjectPublicKeyInfo (`BEGIN PUBLIC KEY`) for public keys.
+// - Base64 of the raw key bytes.
+// - Hex, either space separated bytes as in the original format or one unbroken string.
+// Private keys are either the 32 byte seed or the 64 byte seed followed by the public key.
+use crypto::ed25519;
+
+// The DER that comes before the key bytes. Ed25519 keys have no optional fields, so anything else isn't one.
+// PKCS#8: version 0, the Ed25519 algorithm and the seed as an octet string inside an octet string.
+const PKCS8_PREFIX: [u8; 16] = [0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20];
+// SubjectPublicKeyInfo: the Ed25519 algorithm and the key as a bit string.
+const SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+fn pem_body(text: &str, label: &str) -> Option<Result<Vec<u8>, String>> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let start = text.find(&begin)? + begin.len();
+    let body = match text[start..].find(&end) {
+        Some(stop) => &text[start..start + stop],
+        None => return Some(Err(format!("missing {}", end)))
+    };
+    let joined: String = body.split_whitespace().collect();
+    Some(match base64::decode(&joined) {
+        Ok(der) => Ok(der),
+        Err(e) => Err(format!("invalid base64 in PEM: {}", e))
+    })
+}
+
+// Takes the 32 key bytes from a DER encoding that is exactly the prefix followed by the key.
+fn key_from_der(der: &[u8], prefix: &[u8]) -> Result<Vec<u8>, String> {
+    if der.len() != prefix.len() + 32 || !der.starts_with(prefix) {
+        return Err("not an Ed25519 key".to_string())
+    }
+    Ok(der[prefix.len()..].to_vec())
+}
+
+fn hex_byte(chunk: &str) -> Result<u8, String> {
+    if chunk.len() == 0 || chunk.len() > 2 || !chunk.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("{} is not a hex byte", chunk))
+    }
+    Ok(u8::from_str_radix(chunk, 16).unwrap())
+}
+
+fn raw_bytes(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.trim();
+    if text.split_whitespace().count() > 1 {
+        return text.split_whitespace().map(hex_byte).collect()
+    }
+    // Base64 of a 32 or 64 byte key always ends in padding, so text that is only hex digits is hex.
+    if text.len() % 2 == 0 && text.len() > 0 && text.chars().all(|c| c.is_ascii_hexdigit()) {
+        return (0..text.len()).step_by(2).map(|i| hex_byte(&text[i..i + 2])).collect()
+    }
+    match base64::decode(text) {
+        Ok(bytes) => Ok(bytes),
+        Err(e) => Err(format!("expected PEM, base64 or hex: {}", e))
+    }
+}
+
+// Returns the 64 byte secret key and its public key.
+pub fn parse_private_key(text: &str) -> Result<([u8; 64], [u8; 32]), String> {
+    let bytes = match pem_body(text, "PRIVATE KEY") {
+        Some(der) => key_from_der(&der?, &PKCS8_PREFIX)?,
+        None => raw_bytes(text)?
+    };
+    match bytes.len() {
+        32 => Ok(ed25519::keypair(&bytes)),
+        64 => {
+            let (secret, public) = ed25519::keypair(&bytes[..32]);
+            if public[..] != bytes[32..] {
+                return Err("the public half of the private key does not belong to its seed".to_string())
+            }
+            Ok((secret, public))
+        },
+        n => Err(format!("expected a 32 or 64 byte private key, got {} bytes", n))
+    }
+}
+
+pub fn parse_public_key(text: &str) -> Result<[u8; 32], String> {
+    let bytes = match pem_body(text, "PUBLIC KEY") {
+        Some(der) => key_from_der(&der?, &SPKI_PREFIX)?,
+        None => raw_bytes(text)?
+    };
+    if bytes.len() != 32 {
+        return Err(format!("expected a 32 byte public key, got {} bytes", bytes.len()))
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
@@ -5,12 +5,14 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
 #![allow(unused_imports)]
-use actix_web::{web, App, HttpResponse, HttpServer, Responder, http, guard};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder, http, guard};
 use actix_rt::System;
 use std::env;
+use std::io::Read;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::sync::atomic::AtomicU64;
 use std::future::Future;
 use awc;
 use std::borrow::Borrow;
@@ -26,17 +28,24 @@ use std::collections::hash_map::DefaultHasher;
 use futures::future::{BoxFuture, FutureExt};
 use crate::data::{InterpreterType, Obj};
 use crate::schemas::{Schema};
-use crate::ops::{Op};
-use crate::interpreter::{Globals, conduit_byte_code_interpreter};
+use crate::ops::{Op, Procedure};
+use crate::storage::{Store};
+use crate::interpreter::{Globals, ResponseFormat, conduit_byte_code_interpreter};
+use crate::roles::Keyring;
 mod storage;
 mod locks;
 mod data;
 mod schemas;
 mod ops;
 mod interpreter;
+mod aggregation;
+mod json_schema;
+mod openapi;
+mod roles;
+mod keys;
 
 struct AppData {
-    noop: Vec<Op>,procs: HashMap<String, Vec<Op>>,privateFns: HashSet<String>,schemas: HashMap<String, Schema>,stores: HashMap<String, Schema>,lm_client: Option<etcd_rs::Client>,private_key: [u8; 64],public_key: [u8; 32],db: Option<mongodb::Database>
+    noop: Procedure,procs: HashMap<String, Procedure>,privateFns: HashSet<String>,schemas: HashMap<String, Schema>,stores: HashMap<String, Store>,lm_client: Option<etcd_rs::Client>,keys: Keyring,deployment: String,db: Option<mongodb::Database>
 }
 
 #[derive(Deserialize)]
@@ -50,11 +59,81 @@ enum KernelRequest {
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     let args: Vec<String> = env::args().collect();
-    HttpServer::new(|| {
+    // `openapi` prints the API description of the configured procedures instead of serving them.
+    if args.len() > 1 && args[1] == "openapi" {
+        let deployment = match deployment_name() {
+            Ok(name) => name,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        let doc = openapi::document(&deployment, &procedures_from_env(), &private_procedures_from_env(), &schemas_from_env());
+        println!("{}", serde_json::to_string_pretty(&doc).unwrap());
+        return Ok(())
+    }
+    // `json-schema export` prints the configured schemas as JSON Schema.
+    // `json-schema import` reads a JSON Schema document from stdin and prints it as schemas.
+    if args.len() > 2 && args[1] == "json-schema" {
+        let out = match args[2].as_str() {
+            "export" => Ok(json_schema::export_document(&schemas_from_env())),
+            "import" => {
+                let mut input = String::new();
+                std::io::stdin().read_to_string(&mut input)?;
+                match serde_json::from_str(&input) {
+                    Ok(doc) => json_schema::import_document(&doc)
+                        .map(|schemas| serde_json::to_value(schemas).unwrap())
+                        .map_err(|e| format!("Could not import JSON schema: {}", e)),
+                    Err(e) => Err(format!("Could not parse JSON schema document: {}", e))
+                }
+            },
+            other => Err(format!("Unknown json-schema command {}", other))
+        };
+        let out = match out {
+            Ok(out) => out,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        println!("{}", serde_json::to_string_pretty(&out).unwrap());
+        return Ok(())
+    }
+    let keys = match keyring_from_env() {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let deployment = match deployment_name() {
+        Ok(name) => name,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = check_definitions() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    // Indexes are created once here rather than by every worker.
+    if let Err(e) = ensure_indexes_from_env().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    HttpServer::new(move || {
+        let keys = keys.clone();
+        let deployment = deployment.clone();
         App::new()
-            .data_factory(|| make_app_data())
+            .data_factory(move || make_app_data(keys.clone(), deployment.clone()))
             .service(
                 web::scope("/")
+                    .service(
+                        web::resource("openapi.json")
+                        .guard(guard::Get())
+                        .route(web::get().to(openapi_doc))
+                    )
                     .service(                        
                         web::resource("{func_name}")
                         .guard(guard::Get())
@@ -76,77 +155,238 @@ async fn main() -> std::io::Result<()> {
     .await
 }
 
-async fn process_req(req: KernelRequest, data: web::Data<AppData>) -> impl Responder {
+fn response_format(req: &HttpRequest) -> ResponseFormat {
+    match req.headers().get(http::header::ACCEPT) {
+        Some(accept) => match accept.to_str() {
+            Ok(a) if a.contains("application/x-ndjson") => ResponseFormat::NdJson,
+            _ => ResponseFormat::Json
+        },
+        None => ResponseFormat::Json
+    }
+}
+
+async fn process_req(req: KernelRequest, data: web::Data<AppData>, format: ResponseFormat) -> impl Responder {
+    let ops_run = AtomicU64::new(0);
     let g = Globals {
         schemas: &data.schemas,
         db: data.db.as_ref(),
         stores: &data.stores,
         fns: &data.procs,
         lm: data.lm_client.as_ref(),
-        private_key: &data.private_key,
-        public_key: &data.public_key
+        keys: &data.keys,
+        deployment: &data.deployment,
+        depth: 0,
+        ops_run: &ops_run
     };
     return match req {
-        KernelRequest::Noop => conduit_byte_code_interpreter(vec![], &data.noop, g),
+        KernelRequest::Noop => conduit_byte_code_interpreter("noop", vec![], &data.noop, g, format).await,
         KernelRequest::Exec{proc, arg} => match data.procs.get(&proc) {
-            Some(ops) => {
+            Some(procedure) => {
                 if data.privateFns.contains(&proc) {
                     eprintln!("Attempting to invoke a private function {}", &proc);
-                    conduit_byte_code_interpreter(vec![], &data.noop, g)
+                    conduit_byte_code_interpreter("noop", vec![], &data.noop, g, format).await
                 }else {
-                    conduit_byte_code_interpreter(arg, ops, g)
+                    conduit_byte_code_interpreter(&proc, arg, procedure, g, format).await
                 }
             },
             None => {
                 eprintln!("Invoking non-existent function {}", &proc);
-                conduit_byte_code_interpreter(vec![], &data.noop, g)
+                conduit_byte_code_interpreter("noop", vec![], &data.noop, g, format).await
             }                
         }
-    }.await;
+    };
     
 }
-async fn get_func(data: web::Data<AppData>, path: web::Path<String>, q: web::Query<HashMap<String, InterpreterType>>) -> impl Responder {
+async fn get_func(req: HttpRequest, data: web::Data<AppData>, path: web::Path<String>, q: web::Query<Vec<(String, String)>>) -> impl Responder {
     let func_name = path.into_inner();
-    let args = q.into_inner();
-    return process_req(KernelRequest::Exec{proc: func_name, arg: vec![InterpreterType::Object(Obj(args))]}, data).await;
+    let schema = match data.procs.get(&func_name) {
+        Some(procedure) => procedure.input_schema(),
+        None => None
+    };
+    let args = schemas::coerce_query(schema, q.into_inner(), &data.schemas);
+    return process_req(KernelRequest::Exec{proc: func_name, arg: vec![args]}, data, response_format(&req)).await;
 }
 
-async fn post_func(data: web::Data<AppData>, input: web::Json<InterpreterType>, path: web::Path<String>) -> impl Responder {    
+async fn post_func(req: HttpRequest, data: web::Data<AppData>, input: web::Json<InterpreterType>, path: web::Path<String>) -> impl Responder {    
     let args = vec![input.into_inner()]; 
     let func_name = path.into_inner();        
-    return process_req(KernelRequest::Exec{proc: func_name, arg: args}, data).await;
+    return process_req(KernelRequest::Exec{proc: func_name, arg: args}, data, response_format(&req)).await;
 }
 
-async fn index(data: web::Data<AppData>, input: web::Json<KernelRequest>) -> impl Responder {    
-    let req = input.into_inner();            
-    return process_req(req, data).await;
+async fn index(req: HttpRequest, data: web::Data<AppData>, input: web::Json<KernelRequest>) -> impl Responder {    
+    let kreq = input.into_inner();            
+    return process_req(kreq, data, response_format(&req)).await;
 }
 
-async fn make_app_data() -> Result<AppData, ()> {
-return Ok(AppData {
-    noop: serde_json::from_str(r#####"[]"#####).unwrap(),
-    procs: match env::var("PROCEDURES") {
+async fn openapi_doc(data: web::Data<AppData>) -> impl Responder {
+    HttpResponse::Ok().json(openapi::document(&data.deployment, &data.procs, &data.privateFns, &data.schemas))
+}
+
+// Roles are bound to the deployment by name and its data lives in a database of the same name, so there's no default.
+fn deployment_name() -> Result<String, String> {
+    match env::var("DEPLOYMENT_NAME") {
+        Ok(name) if name.len() > 0 => Ok(name),
+        _ => Err("DEPLOYMENT_NAME must be set".to_string())
+    }
+}
+
+fn procedures_from_env() -> HashMap<String, Procedure> {
+    match env::var("PROCEDURES") {
         Ok(str) => serde_json::from_str(&str).unwrap(),
         Err(e) => {
             eprintln!("Did not find any procedures {}", e);
             HashMap::with_capacity(0)
         }
-    },
-    privateFns: match env::var("PRIVATE_PROCEDURES") {
+    }
+}
+
+fn private_procedures_from_env() -> HashSet<String> {
+    match env::var("PRIVATE_PROCEDURES") {
         Ok(str) => serde_json::from_str(&str).unwrap(),
         Err(e) => HashSet::with_capacity(0)
-    },
-    schemas: match env::var("SCHEMAS") {
+    }
+}
+
+fn schemas_from_env() -> HashMap<String, Schema> {
+    match env::var("SCHEMAS") {
         Ok(str) => serde_json::from_str(&str).unwrap(),
         Err(e) => {
             eprintln!("Did not find any schemas {}", e);
             HashMap::with_capacity(0)
         }
-    },
-    stores: match env::var("STORES") {
+    }
+}
+
+// Workers parse the definitions for themselves. Parsing them once up front stops startup on mistakes, such as invalid patterns,
+// instead of failing inside each worker.
+fn check_definitions() -> Result<(), String> {
+    if let Ok(s) = env::var("PROCEDURES") {
+        if let Err(e) = serde_json::from_str::<HashMap<String, Procedure>>(&s) {
+            return Err(format!("Invalid PROCEDURES: {}", e))
+        }
+    }
+    if let Ok(s) = env::var("SCHEMAS") {
+        if let Err(e) = serde_json::from_str::<HashMap<String, Schema>>(&s) {
+            return Err(format!("Invalid SCHEMAS: {}", e))
+        }
+    }
+    if let Ok(s) = env::var("STORES") {
+        if let Err(e) = serde_json::from_str::<HashMap<String, Store>>(&s) {
+            return Err(format!("Invalid STORES: {}", e))
+        }
+    }
+    Ok(())
+}
+
+// Reads a setting that can be given inline or as the path of a file holding it, e.g. PRIVATE_KEY or PRIVATE_KEY_FILE.
+// Giving both is an error rather than silently preferring one.
+fn setting_or_file(name: &str) -> Result<Option<String>, String> {
+    let file_var = format!("{}_FILE", name);
+    match (env::var(name), env::var(&file_var)) {
+        (Ok(_), Ok(_)) => Err(format!("Only one of {} and {} may be set", name, file_var)),
+        (Ok(value), Err(_)) => Ok(Some(value)),
+        (Err(_), Ok(path)) => match std::fs::read_to_string(&path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) => Err(format!("Could not read {} from {}: {}", name, path, e))
+        },
+        (Err(_), Err(_)) => Ok(None)
+    }
+}
+
+// The active key comes from PRIVATE_KEY, with PUBLIC_KEY derived from it when it isn't given.
+// Keys it replaced can still verify roles they signed: VERIFICATION_KEYS_DIR names a directory with one public key per file.
+// Hidden entries and anything that isn't a file are skipped.
+// MIN_ROLE_VERSION is the oldest role signature version accepted.
+fn keyring_from_env() -> Result<Keyring, String> {
+    let (private_key, public_key) = match setting_or_file("PRIVATE_KEY")? {
+        Some(text) => match keys::parse_private_key(&text) {
+            Ok(pair) => pair,
+            Err(e) => return Err(format!("Invalid PRIVATE_KEY: {}", e))
+        },
+        None => return Err("PRIVATE_KEY or PRIVATE_KEY_FILE must be set".to_string())
+    };
+    if let Some(text) = setting_or_file("PUBLIC_KEY")? {
+        match keys::parse_public_key(&text) {
+            Ok(given) if given == public_key => {},
+            Ok(_) => return Err("PUBLIC_KEY does not match PRIVATE_KEY".to_string()),
+            Err(e) => return Err(format!("Invalid PUBLIC_KEY: {}", e))
+        };
+    }
+    let mut retired = vec![];
+    if let Ok(dir) = env::var("VERIFICATION_KEYS_DIR") {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => return Err(format!("Could not read verification keys from {}: {}", dir, e))
+        };
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => return Err(format!("Could not read verification keys from {}: {}", dir, e))
+            };
+            // Mounted secrets sit alongside hidden entries such as `..data` and timestamped directories, which aren't keys.
+            let hidden = match path.file_name() {
+                Some(name) => name.to_string_lossy().starts_with('.'),
+                None => true
+            };
+            if hidden || !path.is_file() {
+                continue
+            }
+            let parsed = match std::fs::read_to_string(&path) {
+                Ok(contents) => keys::parse_public_key(&contents),
+                Err(e) => Err(e.to_string())
+            };
+            match parsed {
+                Ok(key) => retired.push(key),
+                Err(e) => return Err(format!("Invalid verification key {}: {}", path.display(), e))
+            };
+        }
+    }
+    let min_version = match env::var("MIN_ROLE_VERSION") {
+        Ok(v) => match v.trim().parse::<i64>() {
+            Ok(v) if v >= 1 && v <= roles::CURRENT_VERSION => v,
+            _ => return Err(format!("MIN_ROLE_VERSION must be a signature version from 1 to {}", roles::CURRENT_VERSION))
+        },
+        Err(_) => roles::DEFAULT_MIN_VERSION
+    };
+    Ok(Keyring::new(private_key, public_key, retired, min_version))
+}
+
+fn stores_from_env() -> HashMap<String, Store> {
+    match env::var("STORES") {
         Ok(r) => serde_json::from_str(&r).unwrap(),
         Err(e) => panic!("Did not receive a definition for any stores")
-    },
+    }
+}
+
+async fn connect_mongo(uri: &str) -> Result<Database, String> {
+    let mut options = match mongodb::options::ClientOptions::parse(uri).await {
+        Ok(o) => o,
+        Err(e) => return Err(format!("Invalid mongo connection uri: {}", e))
+    };
+    options.write_concern = Some(mongodb::options::WriteConcern::builder().w(mongodb::options::Acknowledgment::Majority).build());
+    options.read_concern = Some(mongodb::options::ReadConcern::majority());
+    let client = match mongodb::Client::with_options(options) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Failure connecting to mongo: {}", e))
+    };
+    Ok(client.database(&deployment_name()?))
+}
+
+async fn ensure_indexes_from_env() -> Result<(), String> {
+    let uri = match env::var("MONGO_CONNECTION_URI") {
+        Ok(uri) => uri,
+        Err(_) => return Ok(())
+    };
+    let db = connect_mongo(&uri).await?;
+    storage::ensure_indexes(&db, &stores_from_env()).await
+}
+
+async fn make_app_data(keys: Keyring, deployment: String) -> Result<AppData, ()> {
+return Ok(AppData {
+    noop: serde_json::from_str(r#####"[]"#####).unwrap(),
+    procs: procedures_from_env(),
+    privateFns: private_procedures_from_env(),
+    schemas: schemas_from_env(),
     lm_client: match env::var("ETCD_URL") {
         Ok(r) => {
             println!("Attempting to connect to etcd: {}", r);
@@ -172,64 +412,29 @@ return Ok(AppData {
         },
         Err(e) => None
     },
-    private_key: match env::var("PRIVATE_KEY") {
-        Ok(some_str) => {
-            if some_str.len() != 64 * 3  - 1{
-                panic!("Unexpected string length");
-            }
-            let mut u8s: Vec<u8> = Vec::with_capacity(64);                    
-            for chunk in some_str.split_whitespace() {
-                u8s.push(u8::from_str_radix(chunk, 16).unwrap());
-            }
-            let conv: [u8; 64] = match u8s.try_into() {
-                Ok(r) => r,
-                Err(e) => panic!("Failure getting private key: {:?}", e)
-            };
-            conv
-        },
-        Err(e) => panic!("Private key could not be read")
-    },
-    public_key: match env::var("PUBLIC_KEY") {
-        Ok(some_str) => {
-            if some_str.len() != 32 * 3 - 1 {
-                panic!("Unexpected string length");
-            }
-            let mut u8s: Vec<u8> = Vec::with_capacity(32);                    
-            for chunk in some_str.split_whitespace() {
-                u8s.push(u8::from_str_radix(chunk, 16).unwrap());
-            }
-            let conv: [u8; 32] = match u8s.try_into() {
-                Ok(r) => r,
-                Err(e) => panic!("Failure getting public key: {:?}", e)
-            };
-            conv
-        },
-        Err(e) => panic!("Public key could not be read")
-    },
+    keys: keys,
+    deployment: deployment,
     db: match env::var("MONGO_CONNECTION_URI") {
         Ok(uri) => {
-            let mut options = mongodb::options::ClientOptions::parse(&uri).await.unwrap();
-            options.write_concern = Some(mongodb::options::WriteConcern::builder().w(mongodb::options::Acknowledgment::Majority).build());
-            options.read_concern = Some(mongodb::options::ReadConcern::majority());
-            let client = match mongodb::Client::with_options(options) {
-                Ok(r) => r,
-                Err(e) => panic!("Failure connecting to mongo: {}", e)
+            let db = match connect_mongo(&uri).await {
+                Ok(db) => db,
+                Err(e) => panic!("{}", e)
             };
-            let deploymentname = env::var("DEPLOYMENT_NAME").unwrap();
 
             // List the names of the databases in that deployment.
-            let cols = match client.database(&deploymentname).list_collection_names(None).await {
+            let cols = match db.list_collection_names(None).await {
                 Ok(r) => r,
                 Err(e) => panic!("Failure connecting to mongo: {}", e)
             };
             for col in  cols{
                 println!("{}", col);
             }
-            Some(client.database(&deploymentname))
+            Some(db)
         },
         Err(e) => {
             None
         }
-    }
+    },
+    stores: stores_from_env()
     });
 }
@@ -0,0 +1,90 @@
+use std::collections::{HashMap, HashSet};
+use serde_json::{json, Map, Value};
+
+use crate::schemas::{Schema};
+use crate::ops::{Procedure};
+use crate::json_schema;
+
+const REF_PREFIX: &str = "#/components/schemas/";
+
+// Describes every public procedure as an OpenAPI 3.1 document.
+// Each procedure is served with a POST taking its argument as the body and a GET taking it as query parameters.
+pub fn document(title: &str, procs: &HashMap<String, Procedure>, private: &HashSet<String>, schemas: &HashMap<String, Schema>) -> Value {
+    let mut paths = Map::new();
+    for (name, procedure) in procs {
+        if private.contains(name) {
+            continue;
+        }
+        let input = procedure.input_schema();
+        let returns = match &procedure.returns {
+            Some(r) => json_schema::export(r, REF_PREFIX),
+            None => json!({})
+        };
+        let responses = json!({
+            "200": {
+                "description": "The value returned by the procedure",
+                "content": {"application/json": {"schema": returns}}
+            },
+            "400": {"$ref": "#/components/responses/BadRequest"}
+        });
+        let mut post = json!({
+            "operationId": name,
+            "responses": responses.clone()
+        });
+        if let Some(i) = input {
+            post["requestBody"] = json!({
+                "required": !i.is_optional(),
+                "content": {"application/json": {"schema": json_schema::export(i, REF_PREFIX)}}
+            });
+        }
+        let get = json!({
+            "operationId": format!("{}_get", name),
+            "parameters": parameters(input, schemas),
+            "responses": responses
+        });
+        paths.insert(format!("/{}", name), json!({"get": get, "post": post}));
+    }
+    json!({
+        "openapi": "3.1.0",
+        "info": {"title": title, "version": "1.0.0"},
+        "paths": paths,
+        "components": {
+            "schemas": json_schema::export_all(schemas, REF_PREFIX),
+            "responses": {
+                "BadRequest": {
+                    "description": "The request was invalid or the procedure failed",
+                    "content": {"application/json": {"schema": {
+                        "type": "object",
+                        "properties": {
+                            "error": {"type": "string"},
+                            "procedure": {"type": "string"},
+                            "violations": {"type": "array", "items": {"type": "string"}}
+                        }
+                    }}}
+                }
+            }
+        }
+    })
+}
+
+// Query parameters can only be listed for inputs that are objects with declared fields.
+fn parameters(input: Option<&Schema>, schemas: &HashMap<String, Schema>) -> Value {
+    let fields = match input {
+        Some(Schema::TypeAlias(name)) => return parameters(schemas.get(name), schemas),
+        Some(Schema::Object(fields)) => fields,
+        Some(Schema::PolicyObject(fields, _)) => fields,
+        _ => return json!([])
+    };
+    let mut names: Vec<&String> = fields.0.keys().collect();
+    names.sort();
+    Value::Array(names.into_iter().map(|n| {
+        let field = &fields.0[n];
+        json!({
+            "name": n,
+            "in": "query",
+            "required": !field.is_optional(),
+            "explode": true,
+            "schema": json_schema::export(field, REF_PREFIX)
+        })
+    }).collect())
+}
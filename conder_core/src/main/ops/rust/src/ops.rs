@@ -2,17 +2,27 @@
     
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use serde::{Deserialize};
+use std::fmt::Write;
+use serde::{Deserialize, Deserializer};
 use std::collections::hash_map::DefaultHasher;
 use crypto::ed25519;
 use std::hash::{Hash, Hasher};
+use std::cmp::Ordering;
+use regex::Regex;
 
 use ts_rs::{TS, export};
-use crate::data::{InterpreterType, Obj};
-use crate::schemas::{Schema};
-use crate::interpreter::{Context, Globals, ContextState, conduit_byte_code_interpreter_internal};
+use crate::data::{InterpreterType, Obj, Decimal, Timestamp, Binary};
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive, RoundingStrategy};
+use chrono::{DateTime, NaiveDateTime, Duration, Utc, SecondsFormat};
+use crate::schemas::{Schema, Pattern};
+use crate::interpreter::{Context, Globals, ContextState, Output, conduit_byte_code_interpreter_internal, execute_procedure};
 use crate::storage;
+use crate::storage::{QueryOptions, SortKey, Store, Index};
+use futures::stream::StreamExt;
 use crate::locks;
+use crate::aggregation;
+use crate::roles;
+use crate::aggregation::{Stage, GroupField, Accumulator};
 
 #[derive(Deserialize, Clone, TS)]
 #[serde(tag = "kind", content= "data")]
@@ -68,8 +78,10 @@ pub enum Op {
     enforceSchemaInstanceOnHeap{schema: Schema, heap_pos: u64},
     extractFields(Vec<Vec<String>>),
     equal,
+    notEqual,
     less,
     lesseq,
+    greater,
     boolAnd,
     boolOr,
     assertHeapLen(u64),
@@ -83,10 +95,145 @@ pub enum Op {
     lock,
     release,
     signRole,
-    getType
+    getType,
+    queryStorePage{store: String, project: Obj, options: QueryOptions},
+    enforceSchemaOrReject{schema: Schema, heap_pos: u64},
+    aggregateStore{store: String, pipeline: Vec<Stage>},
+    aggregateArray(Vec<Stage>),
+    openStoreCursor(String, Obj),
+    nextInCursor,
+    returnCursor,
+    now,
+    formatTimestamp(String),
+    parseTimestamp(String),
+    bytesToString(String),
+    stringToBytes(String),
+    toDecimal,
+    roundDecimal{places: u32, mode: RoundingMode},
+    nMod,
+    nIntDivide,
+    nPow,
+    nAbs,
+    nMin,
+    nMax,
+    nFloor,
+    nCeil,
+    nRound,
+    stringLen,
+    stringSlice,
+    stringIndexOf,
+    stringContains,
+    stringStartsWith,
+    stringEndsWith,
+    stringSplit,
+    stringTrim,
+    stringUpper,
+    stringLower,
+    stringReplace{all: bool},
+    regexMatch(Pattern),
+    regexCapture(Pattern),
+    parseInt,
+    parseDouble,
+    arraySlice,
+    arrayConcat,
+    arrayReverse,
+    arrayContains,
+    arrayIndexOf,
+    arraySort(Vec<SortKey>),
+    arrayDedup,
+    // Pops an index and an array, then pushes the array without that element followed by the removed element.
+    // Pop the element to keep only the array.
+    arrayRemove,
+    arrayMap(Callback),
+    arrayFilter(Callback),
+    arrayReduce(Callback),
+    arrayAny(Callback),
+    arrayAll(Callback),
+    arrayFind(Callback),
+    objectMerge{deep: bool},
+    objectDelete,
+    objectHasKey,
+    objectValues,
+    objectEntries,
+    objectPick,
+    objectOmit,
+    getPath{negative_indexes: bool},
+    setPath{negative_indexes: bool},
+    // Role schemas don't consult revocation lists, so procedures that accept revocable roles check them with roleRevoked.
+    revokeRole(String),
+    roleRevoked(String)
 }    
-      
 
+// Code that array ops run per element: either a procedure by name or an inline block of ops.
+// Elements are passed on the heap, with the accumulator ahead of the element for reduce.
+#[derive(Deserialize, Clone, TS)]
+#[serde(tag = "kind", content= "data")]
+pub enum Callback {
+    procedure(String),
+    ops(Vec<Op>)
+}
+
+#[derive(Deserialize, Clone, TS)]
+pub enum RoundingMode {
+    halfEven,
+    halfUp,
+    halfDown,
+    up,
+    down,
+    ceiling,
+    floor
+}
+
+impl RoundingMode {
+    fn strategy(&self) -> RoundingStrategy {
+        match self {
+            RoundingMode::halfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::halfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::halfDown => RoundingStrategy::MidpointTowardZero,
+            RoundingMode::up => RoundingStrategy::AwayFromZero,
+            RoundingMode::down => RoundingStrategy::ToZero,
+            RoundingMode::ceiling => RoundingStrategy::ToPositiveInfinity,
+            RoundingMode::floor => RoundingStrategy::ToNegativeInfinity
+        }
+    }
+}
+
+#[derive(Clone, TS)]
+pub struct Procedure {
+    pub ops: Vec<Op>,
+    // Schemas for each argument, checked before the procedure runs.
+    pub params: Option<Vec<Schema>>,
+    // Schema for the returned value, checked after the procedure runs.
+    pub returns: Option<Schema>
+}
+
+// Procedures may still be declared as only their ops.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProcedureDefinition {
+    OpsOnly(Vec<Op>),
+    Full{ops: Vec<Op>, #[serde(default)] params: Option<Vec<Schema>>, #[serde(default)] returns: Option<Schema>}
+}
+
+impl<'de> Deserialize<'de> for Procedure {
+    fn deserialize<D>(deserializer: D) ->  Result<Self, D::Error> where D: Deserializer<'de>{
+        return Ok(match ProcedureDefinition::deserialize(deserializer)? {
+            ProcedureDefinition::OpsOnly(ops) => Procedure {ops, params: None, returns: None},
+            ProcedureDefinition::Full{ops, params, returns} => Procedure {ops, params, returns}
+        });
+    }
+}
+
+impl Procedure {
+    // The declared schema of the first argument, which GET query parameters are converted to.
+    pub fn input_schema(&self) -> Option<&Schema> {
+        match &self.params {
+            Some(params) => params.first(),
+            None => None
+        }
+    }
+}
+      
 impl<'a> Context<'a> {
 
     pub fn pop_stack(&mut self) -> Result<InterpreterType, String> {
@@ -137,9 +284,25 @@ impl InterpreterType {
             InterpreterType::string(s) => Ok(s),
             InterpreterType::int(i) => Ok(i.to_string()),
             InterpreterType::double(d) => Ok(d.to_string()),
+            InterpreterType::decimal(d) => Ok(d.0.to_string()),
             _ => Err("Cannot convert to string".to_string())
         }
     }
+    // Unlike to_str, doesn't stringify numbers.
+    fn expect_str(self) -> Result<String, String> {
+        match self {
+            InterpreterType::string(s) => Ok(s),
+            _ => Err("Expected a string".to_string())
+        }
+    }
+
+    fn to_int(self) -> Result<i64, String> {
+        match self {
+            InterpreterType::int(i) => Ok(i),
+            _ => Err("Expected an integer".to_string())
+        }
+    }
+
     fn to_obj(self) -> Result<HashMap<String, InterpreterType>, String> {
         match self {
             InterpreterType::Object(o) => Ok(o.0),
@@ -161,6 +324,13 @@ impl InterpreterType {
         }
     }
 
+    fn to_handle(self) -> Result<usize, String> {
+        match self {
+            InterpreterType::int(i) if i >= 0 => Ok(i as usize),
+            _ => Err("Expected a cursor handle".to_string())
+        }
+    }
+
     fn try_push(&mut self, data: InterpreterType) -> Result<(), String> {
         match self {
             InterpreterType::Array(r) => {r.push(data); Ok(())},
@@ -169,27 +339,33 @@ impl InterpreterType {
     }
     
     fn get<'a>(&'a mut self, field: InterpreterType) -> Result<Option<&'a mut InterpreterType>, String> {
+        self.get_with(field, false)
+    }
+
+    fn get_with<'a>(&'a mut self, field: InterpreterType, negative_indexes: bool) -> Result<Option<&'a mut InterpreterType>, String> {
         Ok(match self {
             InterpreterType::Object(o) => match field {
                 InterpreterType::string(s) => o.0.get_mut(&s),
                 _ => return Err(format!("Cannot index into object with this type"))
             },
-            InterpreterType::Array(a) => match field {
-                InterpreterType::int(i) =>a.get_mut(i as usize),
-                InterpreterType::double(d) => a.get_mut(d as usize),
-                _ => return Err(format!("Cannot index array with type"))
+            InterpreterType::Array(a) => match array_index(a.len(), &field, negative_indexes)? {
+                Some(i) => a.get_mut(i),
+                None => None
             },
             _ => return Err(format!("cannot index into type"))
         })
     }
 
+    fn set<'a>(&mut self, fields: Vec<InterpreterType>, set_to: InterpreterType) -> Result<(), String> {
+        self.set_with(fields, set_to, false)
+    }
 
-    fn set<'a>(&mut self, mut fields: Vec<InterpreterType>, set_to: InterpreterType) -> Result<(), String> {
+    fn set_with<'a>(&mut self, mut fields: Vec<InterpreterType>, set_to: InterpreterType, negative_indexes: bool) -> Result<(), String> {
         let last_field = fields.pop().safe_unwrap()?;
 
         let mut o_or_a = self;
         for f in fields {
-            o_or_a = o_or_a.get(f)?.safe_unwrap()?;
+            o_or_a = o_or_a.get_with(f, negative_indexes)?.safe_unwrap()?;
         }
 
         match o_or_a {
@@ -197,50 +373,60 @@ impl InterpreterType {
                 InterpreterType::string(s) => o.0.insert(s, set_to),
                 _ => return Err(format!("Cannot index object with this type"))
             },
+            // Assigning one past the end appends.
+            InterpreterType::Array(a) => match array_index(a.len(), &last_field, negative_indexes)? {
+                Some(i) if i < a.len() => Some(std::mem::replace(&mut a[i], set_to)),
+                Some(i) if i == a.len() => {a.push(set_to); None},
+                _ => return Err(format!("index {:?} is out of bounds for an array of length {}", last_field, a.len()))
+            },
             _ => return Err(format!("cannot overwrite type"))
         };
         Ok(())
     }
 
-    fn equals(&self, other: &InterpreterType) -> bool {
-        match (self, other) {
-            (InterpreterType::string(s1), InterpreterType::string(s2)) => s1 == s2,
-            (InterpreterType::int(i1), InterpreterType::int(i2)) => i1 == i2,
-            (InterpreterType::double(d1), InterpreterType::double(d2)) => d1 == d2,
-            (InterpreterType::None, InterpreterType::None) => true,
-            (_, _) => false
+    fn to_decimal(&self) -> Result<rust_decimal::Decimal, String> {
+        match self {
+            InterpreterType::int(i) => Ok(rust_decimal::Decimal::from(*i)),
+            InterpreterType::decimal(d) => Ok(d.0),
+            InterpreterType::double(d) => match rust_decimal::Decimal::from_f64(*d) {
+                Some(converted) => Ok(converted),
+                None => Err(format!("{} cannot be represented as a decimal", d))
+            },
+            InterpreterType::string(s) => Ok(Decimal::parse(s)?.0),
+            _ => Err("Expected a number".to_string())
         }
     }
 
-    fn compare(&self, other: &InterpreterType) -> Result<Compare, String> {
-        let d1 = match self {
-            InterpreterType::int(i1) => *i1 as f64,
-            InterpreterType::double(d1) => *d1,
-            _ => return Err("Can only compare numbers".to_string())
-        };
-        let d2 = match other {
-            InterpreterType::int(i1) => *i1 as f64,
-            InterpreterType::double(d2) => *d2,
-            _ => return Err("Can only compare numbers".to_string())
-        };
-        Ok(match (d1, d2) {
-            (_, _) if d1 < d2 => Compare::Less,
-            (_, _) if d1 > d2 => Compare::Greater,
-            (_, _) => Compare::Equal
-        })        
+    // Arithmetic with a decimal on either side is done in decimal.
+    // Doubles aren't converted implicitly since they're rarely the exact value intended; use toDecimal first.
+    fn decimal_operands(&self, other: &InterpreterType) -> Result<Option<(rust_decimal::Decimal, rust_decimal::Decimal)>, String> {
+        match (self, other) {
+            (InterpreterType::decimal(_), InterpreterType::double(_)) | (InterpreterType::double(_), InterpreterType::decimal(_)) =>
+                Err("cannot mix decimals and doubles, convert with toDecimal first".to_string()),
+            (InterpreterType::decimal(_), InterpreterType::int(_))
+            | (InterpreterType::decimal(_), InterpreterType::decimal(_))
+            | (InterpreterType::int(_), InterpreterType::decimal(_)) => Ok(Some((self.to_decimal()?, other.to_decimal()?))),
+            _ => Ok(None)
+        }
     }
 
     fn plus(&self, other: &InterpreterType) -> Result<InterpreterType, String> {
+        if let Some((l, r)) = self.decimal_operands(other)? {
+            return match l.checked_add(r) {
+                Some(d) => Ok(InterpreterType::decimal(Decimal(d))),
+                None => Err("decimal overflow".to_string())
+            }
+        }
         Ok(match self {
             InterpreterType::int(i1) => match other {
-                InterpreterType::int(i2) => InterpreterType::int(i1 + i2),
-                InterpreterType::double(d2) => InterpreterType::double(*i1 as f64 + d2),
+                InterpreterType::int(i2) => checked_int(i1.checked_add(*i2))?,
+                InterpreterType::double(d2) => checked_double(*i1 as f64 + d2)?,
                 InterpreterType::string(s) => InterpreterType::string(format!("{}{}", i1, s)),
                 _ => return Err(format!("not addable"))
             },
             InterpreterType::double(d1) => match other {
-                InterpreterType::int(i2) => InterpreterType::double(d1 + (*i2 as f64)),
-                InterpreterType::double(d2) => InterpreterType::double(d1 + d2),
+                InterpreterType::int(i2) => checked_double(d1 + (*i2 as f64))?,
+                InterpreterType::double(d2) => checked_double(d1 + d2)?,
                 InterpreterType::string(s) => InterpreterType::string(format!("{}{}", d1, s)),
                 _ => return Err(format!("not addable"))
             }, 
@@ -249,53 +435,224 @@ impl InterpreterType {
                 InterpreterType::double(d) => InterpreterType::string(format!("{}{}", s, d)),
                 InterpreterType::string(d) => InterpreterType::string(format!("{}{}", s, d)),
                 _ =>return Err(format!("not addable"))
-            }
+            },
+            // Adding an int to a timestamp moves it by that many milliseconds.
+            InterpreterType::timestamp(t) => match other {
+                InterpreterType::int(ms) => match t.0.checked_add_signed(Duration::milliseconds(*ms)) {
+                    Some(moved) => InterpreterType::timestamp(Timestamp(moved)),
+                    None => return Err(format!("timestamp out of range"))
+                },
+                _ => return Err(format!("not addable"))
+            },
             _ => return Err(format!("not addable"))
         })
     }
 
     fn minus(&self, other: &InterpreterType) -> Result<InterpreterType, String> {
+        if let Some((l, r)) = self.decimal_operands(other)? {
+            return match l.checked_sub(r) {
+                Some(d) => Ok(InterpreterType::decimal(Decimal(d))),
+                None => Err("decimal overflow".to_string())
+            }
+        }
         Ok(match self {
             InterpreterType::int(i1) => match other {
-                InterpreterType::int(i2) => InterpreterType::int(i1 - i2),
-                InterpreterType::double(d2) => InterpreterType::double(*i1 as f64 - d2),
+                InterpreterType::int(i2) => checked_int(i1.checked_sub(*i2))?,
+                InterpreterType::double(d2) => checked_double(*i1 as f64 - d2)?,
                 _ => return Err(format!("not subtractable"))
             },
             InterpreterType::double(d1) => match other {
-                InterpreterType::int(i2) => InterpreterType::double(d1 - (*i2 as f64)),
-                InterpreterType::double(d2) => InterpreterType::double(d1 - d2),
+                InterpreterType::int(i2) => checked_double(d1 - (*i2 as f64))?,
+                InterpreterType::double(d2) => checked_double(d1 - d2)?,
                 _ => return Err(format!("not subtractable"))
             }, 
+            // The difference between two timestamps is in milliseconds.
+            InterpreterType::timestamp(t) => match other {
+                InterpreterType::int(ms) => match t.0.checked_sub_signed(Duration::milliseconds(*ms)) {
+                    Some(moved) => InterpreterType::timestamp(Timestamp(moved)),
+                    None => return Err(format!("timestamp out of range"))
+                },
+                InterpreterType::timestamp(t2) => InterpreterType::int(t.0.signed_duration_since(t2.0).num_milliseconds()),
+                _ => return Err(format!("not subtractable"))
+            },
             _ => return Err(format!("not subtractable"))
         })
     }
 
+    fn is_zero(&self) -> bool {
+        match self {
+            InterpreterType::int(i) => *i == 0,
+            InterpreterType::double(d) => *d == 0.0,
+            InterpreterType::decimal(d) => d.0.is_zero(),
+            _ => false
+        }
+    }
+
+    // Dividing two ints truncates toward zero.
     fn divide(&self, other: &InterpreterType) -> Result<InterpreterType, String> {
+        if other.is_zero() {
+            return Err("division by zero".to_string())
+        }
+        if let Some((l, r)) = self.decimal_operands(other)? {
+            return match l.checked_div(r) {
+                Some(d) => Ok(InterpreterType::decimal(Decimal(d))),
+                None => Err("decimal overflow".to_string())
+            }
+        }
         Ok(match self {
             InterpreterType::int(i1) => match other {
-                InterpreterType::int(i2) => InterpreterType::int(i1 / i2),
-                InterpreterType::double(d2) => InterpreterType::double(*i1 as f64 / d2),
+                InterpreterType::int(i2) => checked_int(i1.checked_div(*i2))?,
+                InterpreterType::double(d2) => checked_double(*i1 as f64 / d2)?,
                 _ => return Err(format!("not divisible"))
             },
             InterpreterType::double(d1) => match other {
-                InterpreterType::int(i2) => InterpreterType::double(d1 / (*i2 as f64)),
-                InterpreterType::double(d2) => InterpreterType::double(d1 / d2),
+                InterpreterType::int(i2) => checked_double(d1 / (*i2 as f64))?,
+                InterpreterType::double(d2) => checked_double(d1 / d2)?,
                 _ => return Err(format!("not divisible"))
             }, 
             _ => return Err(format!("not divisible"))
         })
     }
 
+    // Rounds the quotient toward negative infinity. Decimals stay decimal and everything else becomes an int.
+    fn int_divide(&self, other: &InterpreterType) -> Result<InterpreterType, String> {
+        if other.is_zero() {
+            return Err("division by zero".to_string())
+        }
+        if let Some((l, r)) = self.decimal_operands(other)? {
+            return match l.checked_div(r) {
+                Some(d) => Ok(InterpreterType::decimal(Decimal(d.floor()))),
+                None => Err("decimal overflow".to_string())
+            }
+        }
+        match (self, other) {
+            (InterpreterType::int(l), InterpreterType::int(r)) => {
+                let q = match l.checked_div(*r) {
+                    Some(q) => q,
+                    None => return Err("integer overflow".to_string())
+                };
+                Ok(InterpreterType::int(if l % r != 0 && (*l < 0) != (*r < 0) {q - 1} else {q}))
+            },
+            (l, r) => {
+                let q = (l.to_double()? / r.to_double()?).floor();
+                if !q.is_finite() || q < i64::MIN as f64 || q >= i64::MAX as f64 {
+                    return Err("integer overflow".to_string())
+                }
+                Ok(InterpreterType::int(q as i64))
+            }
+        }
+    }
+
+    // The remainder takes the sign of the divisor, so that l == r * intDivide(l, r) + modulo(l, r).
+    fn modulo(&self, other: &InterpreterType) -> Result<InterpreterType, String> {
+        if other.is_zero() {
+            return Err("division by zero".to_string())
+        }
+        if let Some((l, r)) = self.decimal_operands(other)? {
+            let m = match l.checked_rem(r) {
+                Some(m) => m,
+                None => return Err("decimal overflow".to_string())
+            };
+            return Ok(InterpreterType::decimal(Decimal(
+                if !m.is_zero() && m.is_sign_negative() != r.is_sign_negative() {m + r} else {m}
+            )))
+        }
+        match (self, other) {
+            (InterpreterType::int(l), InterpreterType::int(r)) => {
+                let m = l.wrapping_rem(*r);
+                Ok(InterpreterType::int(if m != 0 && (m < 0) != (*r < 0) {m + r} else {m}))
+            },
+            (l, r) => {
+                let (l, r) = (l.to_double()?, r.to_double()?);
+                let m = l % r;
+                checked_double(if m != 0.0 && (m < 0.0) != (r < 0.0) {m + r} else {m})
+            }
+        }
+    }
+
+    // Ints raised to non-negative ints stay ints, decimals stay decimals, and everything else is a double.
+    fn pow(&self, other: &InterpreterType) -> Result<InterpreterType, String> {
+        match (self, other) {
+            (InterpreterType::int(base), InterpreterType::int(exp)) if *exp >= 0 => {
+                if *exp > u32::MAX as i64 {
+                    return Err("integer overflow".to_string())
+                }
+                checked_int(base.checked_pow(*exp as u32))
+            },
+            (InterpreterType::decimal(base), InterpreterType::int(exp)) => {
+                let overflow = || "decimal overflow".to_string();
+                let mut result = rust_decimal::Decimal::from(1);
+                let mut square = base.0;
+                let mut remaining = (*exp as i128).abs();
+                while remaining > 0 {
+                    if remaining & 1 == 1 {
+                        result = result.checked_mul(square).ok_or_else(overflow)?;
+                    }
+                    remaining >>= 1;
+                    if remaining > 0 {
+                        square = square.checked_mul(square).ok_or_else(overflow)?;
+                    }
+                }
+                if *exp < 0 {
+                    result = match rust_decimal::Decimal::from(1).checked_div(result) {
+                        Some(r) => r,
+                        None => return Err("division by zero".to_string())
+                    };
+                }
+                Ok(InterpreterType::decimal(Decimal(result)))
+            },
+            (InterpreterType::decimal(_), _) | (_, InterpreterType::decimal(_)) => Err("decimals can only be raised to int powers".to_string()),
+            (base, exp) => checked_double(base.to_double()?.powf(exp.to_double()?))
+        }
+    }
+
+    fn abs(&self) -> Result<InterpreterType, String> {
+        match self {
+            InterpreterType::int(i) => checked_int(i.checked_abs()),
+            InterpreterType::double(d) => Ok(InterpreterType::double(d.abs())),
+            InterpreterType::decimal(d) => Ok(InterpreterType::decimal(Decimal(d.0.abs()))),
+            _ => Err("Expected a number".to_string())
+        }
+    }
+
+    // Rounds to a whole number without changing the type. Halves round away from zero.
+    fn round_with(&self, double: fn(f64) -> f64, decimal: fn(&rust_decimal::Decimal) -> rust_decimal::Decimal) -> Result<InterpreterType, String> {
+        match self {
+            InterpreterType::int(i) => Ok(InterpreterType::int(*i)),
+            InterpreterType::double(d) => Ok(InterpreterType::double(double(*d))),
+            InterpreterType::decimal(d) => Ok(InterpreterType::decimal(Decimal(decimal(&d.0)))),
+            _ => Err("Expected a number".to_string())
+        }
+    }
+
+    fn to_double(&self) -> Result<f64, String> {
+        match self {
+            InterpreterType::int(i) => Ok(*i as f64),
+            InterpreterType::double(d) => Ok(*d),
+            InterpreterType::decimal(d) => match d.0.to_f64() {
+                Some(f) => Ok(f),
+                None => Err("decimal cannot be represented as a double".to_string())
+            },
+            _ => Err("Expected a number".to_string())
+        }
+    }
+
     fn multiply(&self, other: &InterpreterType) -> Result<InterpreterType, String> {
+        if let Some((l, r)) = self.decimal_operands(other)? {
+            return match l.checked_mul(r) {
+                Some(d) => Ok(InterpreterType::decimal(Decimal(d))),
+                None => Err("decimal overflow".to_string())
+            }
+        }
         Ok(match self {
             InterpreterType::int(i1) => match other {
-                InterpreterType::int(i2) => InterpreterType::int(i1 * i2),
-                InterpreterType::double(d2) => InterpreterType::double(*i1 as f64 * d2),
+                InterpreterType::int(i2) => checked_int(i1.checked_mul(*i2))?,
+                InterpreterType::double(d2) => checked_double(*i1 as f64 * d2)?,
                 _ => return Err(format!("cannot multiply"))
             },
             InterpreterType::double(d1) => match other {
-                InterpreterType::int(i2) => InterpreterType::double(d1 * (*i2 as f64)),
-                InterpreterType::double(d2) => InterpreterType::double(d1 * d2),
+                InterpreterType::int(i2) => checked_double(d1 * (*i2 as f64))?,
+                InterpreterType::double(d2) => checked_double(d1 * d2)?,
                 _ => return Err(format!("cannot multiply"))
             }, 
             _ => return Err(format!("cannot multiply"))
@@ -303,10 +660,99 @@ impl InterpreterType {
     }
 }
 
-enum Compare {
-    Less,
-    Greater,
-    Equal
+fn checked_int(result: Option<i64>) -> Result<InterpreterType, String> {
+    match result {
+        Some(i) => Ok(InterpreterType::int(i)),
+        None => Err("integer overflow".to_string())
+    }
+}
+
+fn checked_double(result: f64) -> Result<InterpreterType, String> {
+    if result.is_nan() {
+        Err("result is not a number".to_string())
+    } else if result.is_infinite() {
+        Err("floating point overflow".to_string())
+    } else {
+        Ok(InterpreterType::double(result))
+    }
+}
+
+// The inner error is a rejection from the callback, which the calling procedure passes along.
+async fn call<'a>(callback: &'a Callback, args: Vec<InterpreterType>, globals: &'a Globals<'a>) -> Result<Result<InterpreterType, InterpreterType>, String> {
+    let nested = globals.nested()?;
+    let output = match callback {
+        Callback::procedure(name) => {
+            let procedure = match globals.fns.get(name) {
+                Some(p) => p,
+                None => return Err(format!("Procedure {} does not exist", name))
+            };
+            execute_procedure(name, procedure, args, &nested).await?
+        },
+        Callback::ops(ops) => conduit_byte_code_interpreter_internal(Context::new(ops, args), &nested).await?
+    };
+    match output {
+        Output::Value(v) => Ok(Ok(v)),
+        Output::Rejected(reason) => Ok(Err(reason)),
+        Output::Stream(_) => Err("Cannot return a cursor from a callback".to_string())
+    }
+}
+
+// Fields from the right win. A deep merge combines objects found under the same key instead of replacing them.
+fn merge(left: &mut HashMap<String, InterpreterType>, right: HashMap<String, InterpreterType>, deep: bool) {
+    for (k, v) in right {
+        let v = match (left.get_mut(&k), v) {
+            (Some(InterpreterType::Object(l)), InterpreterType::Object(r)) if deep => {
+                merge(&mut l.0, r.0, deep);
+                continue
+            },
+            (_, v) => v
+        };
+        left.insert(k, v);
+    }
+}
+
+fn sorted_entries(obj: HashMap<String, InterpreterType>) -> Vec<(String, InterpreterType)> {
+    let mut entries: Vec<(String, InterpreterType)> = obj.into_iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries
+}
+
+fn to_keys(keys: InterpreterType) -> Result<Vec<String>, String> {
+    keys.to_array()?.into_iter().map(|k| k.expect_str()).collect()
+}
+
+// Resolves an array index, returning none when it's past either end.
+// Negative indexes count back from the end only when enabled and are an error otherwise.
+fn array_index(len: usize, field: &InterpreterType, negative_indexes: bool) -> Result<Option<usize>, String> {
+    let i = match field {
+        InterpreterType::int(i) => *i,
+        InterpreterType::double(d) if d.fract() == 0.0 && d.abs() < 9.2e18 => *d as i64,
+        InterpreterType::double(d) => return Err(format!("array index {} is not an integer", d)),
+        _ => return Err(format!("Cannot index array with type"))
+    };
+    if i >= 0 {
+        return Ok(Some(i as usize))
+    }
+    if !negative_indexes {
+        return Err(format!("negative array index {}", i))
+    }
+    let from_end = len as i64 + i;
+    Ok(if from_end < 0 {None} else {Some(from_end as usize)})
+}
+
+// Resolves slice bounds the way javascript does: negative positions count from the end and everything is clamped to the length.
+fn slice_bounds(len: usize, start: i64, end: Option<i64>) -> (usize, usize) {
+    let resolve = |i: i64| if i < 0 {
+        (len as i64 + i).max(0) as usize
+    } else {
+        (i as usize).min(len)
+    };
+    let start = resolve(start);
+    let end = match end {
+        Some(e) => resolve(e),
+        None => len
+    };
+    (start, end.max(start))
 }
 
 impl <'a> Context<'a>  {
@@ -321,7 +767,7 @@ impl <'a> Context<'a>  {
                     Some(schema) => schema.adheres(
                         &self.pop_stack()?,
                         globals.schemas,
-                        globals.public_key),
+                        &globals.verifier()),
                     None => return Err(format!("Schema does not exist"))
                 };
                 self.stack.push(InterpreterType::bool(b));
@@ -503,24 +949,28 @@ impl <'a> Context<'a>  {
             Op::enforceSchemaOnHeap{schema, heap_pos} => {                
                 let v = self.heap.get(*heap_pos as usize).safe_unwrap()?;
                 let s = globals.schemas.get(schema).safe_unwrap()?;
-                self.stack.push(InterpreterType::bool(s.adheres(v, globals.schemas, globals.public_key)));
+                let res = s.sanitize(v, globals.schemas, &globals.verifier());
+                self.stack.push(InterpreterType::bool(res.is_ok()));
+                if let Ok(sanitized) = res {
+                    self.heap[*heap_pos as usize] = sanitized;
+                }
                 self.advance()
             },
             Op::insertFromHeap{heap_pos, store} => {                
                 let v = self.heap.get(*heap_pos as usize).safe_unwrap()?;
                 let db = globals.db.safe_unwrap()?; 
-                storage::append(db, store, v).await?;
+                storage::append(db, store, v, &globals.validator(store)?).await?;
                 self.advance()        
             },
             Op::insertFromStack(op_param) => {
                 let insert_elt = self.pop_stack()?;
                 let db = globals.db.safe_unwrap()?;
-                storage::append(db, op_param, &insert_elt).await?;
+                storage::append(db, op_param, &insert_elt, &globals.validator(op_param)?).await?;
                 self.advance()
             },
             Op::getAllFromStore(op_param) => {                
                 let db = globals.db.safe_unwrap()?;
-                let res = storage::query(db, op_param, &HashMap::new(), &HashMap::new()).await?;
+                let res = storage::query(db, op_param, &HashMap::new(), &HashMap::new(), &QueryOptions::default()).await?;
                 self.stack.push(res);
                 self.advance()
             },
@@ -531,7 +981,7 @@ impl <'a> Context<'a>  {
             },
             Op::queryStore(param0, param1) => {                
                 let db = globals.db.safe_unwrap()?;
-                let res = storage::query(db, &param0, &param1.0, &self.pop_stack()?.to_obj()?).await?;
+                let res = storage::query(db, &param0, &param1.0, &self.pop_stack()?.to_obj()?, &QueryOptions::default()).await?;
                 self.stack.push(res);
                 self.advance()        
             },
@@ -623,6 +1073,156 @@ impl <'a> Context<'a>  {
                 self.stack.push(v);
                 self.advance()                
             },
+            Op::arraySlice => {
+                let end = match self.pop_stack()? {
+                    InterpreterType::None => None,
+                    other => Some(other.to_int()?)
+                };
+                let start = self.pop_stack()?.to_int()?;
+                let mut arr = self.pop_stack()?.to_array()?;
+                let (start, end) = slice_bounds(arr.len(), start, end);
+                self.stack.push(InterpreterType::Array(arr.drain(start..end).collect()));
+                self.advance()
+            },
+            Op::arrayConcat => {
+                let mut right = self.pop_stack()?.to_array()?;
+                let mut left = self.pop_stack()?.to_array()?;
+                left.append(&mut right);
+                self.stack.push(InterpreterType::Array(left));
+                self.advance()
+            },
+            Op::arrayReverse => {
+                let mut arr = self.pop_stack()?.to_array()?;
+                arr.reverse();
+                self.stack.push(InterpreterType::Array(arr));
+                self.advance()
+            },
+            Op::arrayContains => {
+                let needle = self.pop_stack()?;
+                let arr = self.pop_stack()?.to_array()?;
+                self.stack.push(InterpreterType::bool(arr.iter().any(|v| v.deep_equals(&needle))));
+                self.advance()
+            },
+            Op::arrayIndexOf => {
+                let needle = self.pop_stack()?;
+                let arr = self.pop_stack()?.to_array()?;
+                let index = match arr.iter().position(|v| v.deep_equals(&needle)) {
+                    Some(i) => i as i64,
+                    None => -1
+                };
+                self.stack.push(InterpreterType::int(index));
+                self.advance()
+            },
+            Op::arraySort(keys) => {
+                let mut arr = self.pop_stack()?.to_array()?;
+                // Without keys the elements themselves are sorted ascending.
+                if keys.is_empty() {
+                    arr.sort_by(|l, r| l.total_cmp(r));
+                } else {
+                    aggregation::sort_by_keys(&mut arr, keys);
+                }
+                self.stack.push(InterpreterType::Array(arr));
+                self.advance()
+            },
+            Op::arrayDedup => {
+                let arr = self.pop_stack()?.to_array()?;
+                self.stack.push(InterpreterType::Array(aggregation::dedup(arr)));
+                self.advance()
+            },
+            Op::arrayRemove => {
+                let index = self.pop_stack()?.to_int()?;
+                let mut arr = self.pop_stack()?.to_array()?;
+                if index < 0 || index as usize >= arr.len() {
+                    return Err(format!("index {} is out of bounds for an array of length {}", index, arr.len()))
+                }
+                let removed = arr.remove(index as usize);
+                self.stack.push(InterpreterType::Array(arr));
+                self.stack.push(removed);
+                self.advance()
+            },
+            Op::arrayMap(callback) => {
+                let arr = self.pop_stack()?.to_array()?;
+                let mut mapped = Vec::with_capacity(arr.len());
+                for v in arr {
+                    match call(callback, vec![v], globals).await? {
+                        Ok(result) => mapped.push(result),
+                        Err(reason) => return Ok(ContextState::Reject(reason))
+                    };
+                }
+                self.stack.push(InterpreterType::Array(mapped));
+                self.advance()
+            },
+            Op::arrayFilter(callback) => {
+                let arr = self.pop_stack()?.to_array()?;
+                let mut kept = vec![];
+                for v in arr {
+                    match call(callback, vec![v.clone()], globals).await? {
+                        Ok(result) => if result.to_bool()? {
+                            kept.push(v);
+                        },
+                        Err(reason) => return Ok(ContextState::Reject(reason))
+                    };
+                }
+                self.stack.push(InterpreterType::Array(kept));
+                self.advance()
+            },
+            Op::arrayReduce(callback) => {
+                let mut acc = self.pop_stack()?;
+                let arr = self.pop_stack()?.to_array()?;
+                for v in arr {
+                    acc = match call(callback, vec![acc, v], globals).await? {
+                        Ok(result) => result,
+                        Err(reason) => return Ok(ContextState::Reject(reason))
+                    };
+                }
+                self.stack.push(acc);
+                self.advance()
+            },
+            Op::arrayAny(callback) => {
+                let arr = self.pop_stack()?.to_array()?;
+                let mut found = false;
+                for v in arr {
+                    match call(callback, vec![v], globals).await? {
+                        Ok(result) => if result.to_bool()? {
+                            found = true;
+                            break
+                        },
+                        Err(reason) => return Ok(ContextState::Reject(reason))
+                    };
+                }
+                self.stack.push(InterpreterType::bool(found));
+                self.advance()
+            },
+            Op::arrayAll(callback) => {
+                let arr = self.pop_stack()?.to_array()?;
+                let mut all = true;
+                for v in arr {
+                    match call(callback, vec![v], globals).await? {
+                        Ok(result) => if !result.to_bool()? {
+                            all = false;
+                            break
+                        },
+                        Err(reason) => return Ok(ContextState::Reject(reason))
+                    };
+                }
+                self.stack.push(InterpreterType::bool(all));
+                self.advance()
+            },
+            Op::arrayFind(callback) => {
+                let arr = self.pop_stack()?.to_array()?;
+                let mut found = InterpreterType::None;
+                for v in arr {
+                    match call(callback, vec![v.clone()], globals).await? {
+                        Ok(result) => if result.to_bool()? {
+                            found = v;
+                            break
+                        },
+                        Err(reason) => return Ok(ContextState::Reject(reason))
+                    };
+                }
+                self.stack.push(found);
+                self.advance()
+            },
             Op::storeLen(op_param) => {                
                 let filter = self.pop_stack()?.to_obj()?;
                 let db = globals.db.safe_unwrap()?;
@@ -638,7 +1238,7 @@ impl <'a> Context<'a>  {
                 let query_doc = self.pop_stack()?;
                 let update_doc =  self.pop_stack()?;
                 let db = globals.db.safe_unwrap()?;
-                let res = storage::find_and_update_one(db, store, *upsert, &query_doc, &update_doc).await?;
+                let res = storage::find_and_update_one(db, store, *upsert, &query_doc, &update_doc, &globals.validator(store)?).await?;
                 self.stack.push(res);
                 self.advance()        
             },
@@ -646,7 +1246,7 @@ impl <'a> Context<'a>  {
                 let query_doc = self.pop_stack()?.to_obj()?;
                 let update_doc =  self.pop_stack()?.to_obj()?;
                 let db = globals.db.safe_unwrap()?;
-                let res = storage::replace_one(db, param0, &query_doc, &update_doc, *param1).await?;
+                let res = storage::replace_one(db, param0, &query_doc, &update_doc, *param1, &globals.validator(param0)?).await?;
                 self.stack.push(InterpreterType::bool(res));
                 self.advance()        
             },
@@ -670,7 +1270,11 @@ impl <'a> Context<'a>  {
             },
             Op::enforceSchemaInstanceOnHeap{heap_pos, schema} => {                
                 let v = self.heap.get(*heap_pos as usize).safe_unwrap()?;
-                self.stack.push(InterpreterType::bool(schema.adheres(v, globals.schemas, globals.public_key)));
+                let res = schema.sanitize(v, globals.schemas, &globals.verifier());
+                self.stack.push(InterpreterType::bool(res.is_ok()));
+                if let Ok(sanitized) = res {
+                    self.heap[*heap_pos as usize] = sanitized;
+                }
                 self.advance()        
             },
             Op::extractFields(op_param) => {                
@@ -690,27 +1294,31 @@ impl <'a> Context<'a>  {
                 let first = self.pop_stack()?;
                 let second = self.pop_stack()?;
 
-                self.stack.push(InterpreterType::bool(first.equals(&second)));
+                self.stack.push(InterpreterType::bool(first.deep_equals(&second)));
                 self.advance()        
             },
+            Op::notEqual => {
+                let first = self.pop_stack()?;
+                let second = self.pop_stack()?;
+                self.stack.push(InterpreterType::bool(!first.deep_equals(&second)));
+                self.advance()
+            },
             Op::less => {                
                 let right = self.pop_stack()?;
                 let left = self.pop_stack()?;    
-                self.stack.push(InterpreterType::bool(match left.compare(&right)? {
-                    Compare::Less => true,
-                    _ => false
-                }));            
+                self.stack.push(InterpreterType::bool(left.total_cmp(&right) == Ordering::Less));            
                 self.advance()        
             },
             Op::lesseq => {                
                 let right = self.pop_stack()?;
                 let left = self.pop_stack()?;
-                self.stack.push(InterpreterType::bool(
-                    match left.compare(&right)? {
-                        Compare::Greater => false,
-                        _ => true
-                    }
-                ));            
+                self.stack.push(InterpreterType::bool(left.total_cmp(&right) != Ordering::Greater));            
+                self.advance()
+            },
+            Op::greater => {
+                let right = self.pop_stack()?;
+                let left = self.pop_stack()?;
+                self.stack.push(InterpreterType::bool(left.total_cmp(&right) == Ordering::Greater));
                 self.advance()
             },
             Op::boolAnd => {
@@ -781,6 +1389,273 @@ impl <'a> Context<'a>  {
                 self.stack.push(result);
                 self.advance()
             },
+            Op::nMod => {
+                let right = self.pop_stack()?;
+                let left = self.pop_stack()?;
+                let result = left.modulo(&right)?;
+                self.stack.push(result);
+                self.advance()
+            },
+            Op::nIntDivide => {
+                let right = self.pop_stack()?;
+                let left = self.pop_stack()?;
+                let result = left.int_divide(&right)?;
+                self.stack.push(result);
+                self.advance()
+            },
+            Op::nPow => {
+                let right = self.pop_stack()?;
+                let left = self.pop_stack()?;
+                let result = left.pow(&right)?;
+                self.stack.push(result);
+                self.advance()
+            },
+            Op::nAbs => {
+                let result = self.pop_stack()?.abs()?;
+                self.stack.push(result);
+                self.advance()
+            },
+            Op::nMin => {
+                let right = self.pop_stack()?;
+                let left = self.pop_stack()?;
+                left.to_double()?;
+                right.to_double()?;
+                let result = match left.total_cmp(&right) {
+                    Ordering::Greater => right,
+                    _ => left
+                };
+                self.stack.push(result);
+                self.advance()
+            },
+            Op::nMax => {
+                let right = self.pop_stack()?;
+                let left = self.pop_stack()?;
+                left.to_double()?;
+                right.to_double()?;
+                let result = match left.total_cmp(&right) {
+                    Ordering::Less => right,
+                    _ => left
+                };
+                self.stack.push(result);
+                self.advance()
+            },
+            Op::nFloor => {
+                let result = self.pop_stack()?.round_with(f64::floor, rust_decimal::Decimal::floor)?;
+                self.stack.push(result);
+                self.advance()
+            },
+            Op::nCeil => {
+                let result = self.pop_stack()?.round_with(f64::ceil, rust_decimal::Decimal::ceil)?;
+                self.stack.push(result);
+                self.advance()
+            },
+            Op::nRound => {
+                let result = self.pop_stack()?.round_with(f64::round, |d| d.round_dp_with_strategy(0, RoundingStrategy::MidpointAwayFromZero))?;
+                self.stack.push(result);
+                self.advance()
+            },
+            Op::stringLen => {
+                let s = self.pop_stack()?.expect_str()?;
+                self.stack.push(InterpreterType::int(s.chars().count() as i64));
+                self.advance()
+            },
+            Op::stringSlice => {
+                let end = match self.pop_stack()? {
+                    InterpreterType::None => None,
+                    other => Some(other.to_int()?)
+                };
+                let start = self.pop_stack()?.to_int()?;
+                let s = self.pop_stack()?.expect_str()?;
+                let (start, end) = slice_bounds(s.chars().count(), start, end);
+                self.stack.push(InterpreterType::string(s.chars().skip(start).take(end - start).collect()));
+                self.advance()
+            },
+            Op::stringIndexOf => {
+                let needle = self.pop_stack()?.expect_str()?;
+                let haystack = self.pop_stack()?.expect_str()?;
+                let index = match haystack.find(&needle) {
+                    Some(byte_index) => haystack[..byte_index].chars().count() as i64,
+                    None => -1
+                };
+                self.stack.push(InterpreterType::int(index));
+                self.advance()
+            },
+            Op::stringContains => {
+                let needle = self.pop_stack()?.expect_str()?;
+                let haystack = self.pop_stack()?.expect_str()?;
+                self.stack.push(InterpreterType::bool(haystack.contains(&needle)));
+                self.advance()
+            },
+            Op::stringStartsWith => {
+                let prefix = self.pop_stack()?.expect_str()?;
+                let s = self.pop_stack()?.expect_str()?;
+                self.stack.push(InterpreterType::bool(s.starts_with(&prefix)));
+                self.advance()
+            },
+            Op::stringEndsWith => {
+                let suffix = self.pop_stack()?.expect_str()?;
+                let s = self.pop_stack()?.expect_str()?;
+                self.stack.push(InterpreterType::bool(s.ends_with(&suffix)));
+                self.advance()
+            },
+            Op::stringSplit => {
+                let separator = self.pop_stack()?.expect_str()?;
+                let s = self.pop_stack()?.expect_str()?;
+                // An empty separator splits into characters rather than yielding empty strings at either end.
+                let parts = if separator.is_empty() {
+                    s.chars().map(|c| InterpreterType::string(c.to_string())).collect()
+                } else {
+                    s.split(separator.as_str()).map(|p| InterpreterType::string(p.to_string())).collect()
+                };
+                self.stack.push(InterpreterType::Array(parts));
+                self.advance()
+            },
+            Op::stringTrim => {
+                let s = self.pop_stack()?.expect_str()?;
+                self.stack.push(InterpreterType::string(s.trim().to_string()));
+                self.advance()
+            },
+            Op::stringUpper => {
+                let s = self.pop_stack()?.expect_str()?;
+                self.stack.push(InterpreterType::string(s.to_uppercase()));
+                self.advance()
+            },
+            Op::stringLower => {
+                let s = self.pop_stack()?.expect_str()?;
+                self.stack.push(InterpreterType::string(s.to_lowercase()));
+                self.advance()
+            },
+            Op::stringReplace{all} => {
+                let replacement = self.pop_stack()?.expect_str()?;
+                let pattern = self.pop_stack()?.expect_str()?;
+                let s = self.pop_stack()?.expect_str()?;
+                let replaced = if *all {
+                    s.replace(&pattern, &replacement)
+                } else {
+                    s.replacen(&pattern, &replacement, 1)
+                };
+                self.stack.push(InterpreterType::string(replaced));
+                self.advance()
+            },
+            Op::regexMatch(pattern) => {
+                let regex = &pattern.0;
+                let s = self.pop_stack()?.expect_str()?;
+                self.stack.push(InterpreterType::bool(regex.is_match(&s)));
+                self.advance()
+            },
+            Op::regexCapture(pattern) => {
+                let regex = &pattern.0;
+                let s = self.pop_stack()?.expect_str()?;
+                // The whole match comes first, followed by each group. Groups that didn't participate are none.
+                let captures = match regex.captures(&s) {
+                    Some(c) => InterpreterType::Array(c.iter().map(|m| match m {
+                        Some(m) => InterpreterType::string(m.as_str().to_string()),
+                        None => InterpreterType::None
+                    }).collect()),
+                    None => InterpreterType::None
+                };
+                self.stack.push(captures);
+                self.advance()
+            },
+            Op::parseInt => {
+                let s = self.pop_stack()?.expect_str()?;
+                match s.trim().parse::<i64>() {
+                    Ok(i) => self.stack.push(InterpreterType::int(i)),
+                    Err(_) => return Err(format!("{} is not an integer", s))
+                };
+                self.advance()
+            },
+            Op::parseDouble => {
+                let s = self.pop_stack()?.expect_str()?;
+                match s.trim().parse::<f64>() {
+                    Ok(d) if d.is_finite() => self.stack.push(InterpreterType::double(d)),
+                    _ => return Err(format!("{} is not a number", s))
+                };
+                self.advance()
+            },
+            Op::objectMerge{deep} => {
+                let right = self.pop_stack()?.to_obj()?;
+                let mut left = self.pop_stack()?.to_obj()?;
+                merge(&mut left, right, *deep);
+                self.stack.push(InterpreterType::Object(Obj(left)));
+                self.advance()
+            },
+            Op::objectDelete => {
+                let key = self.pop_stack()?.expect_str()?;
+                let mut obj = self.pop_stack()?.to_obj()?;
+                obj.remove(&key);
+                self.stack.push(InterpreterType::Object(Obj(obj)));
+                self.advance()
+            },
+            Op::objectHasKey => {
+                let key = self.pop_stack()?.expect_str()?;
+                let obj = self.pop_stack()?.to_obj()?;
+                self.stack.push(InterpreterType::bool(obj.contains_key(&key)));
+                self.advance()
+            },
+            // Values and entries come out in key order so results are deterministic.
+            Op::objectValues => {
+                let obj = self.pop_stack()?.to_obj()?;
+                let values = sorted_entries(obj).into_iter().map(|(_, v)| v).collect();
+                self.stack.push(InterpreterType::Array(values));
+                self.advance()
+            },
+            Op::objectEntries => {
+                let obj = self.pop_stack()?.to_obj()?;
+                let entries = sorted_entries(obj).into_iter().map(|(k, v)| {
+                    let mut entry = HashMap::with_capacity(2);
+                    entry.insert("_key".to_string(), InterpreterType::string(k));
+                    entry.insert("_val".to_string(), v);
+                    InterpreterType::Object(Obj(entry))
+                }).collect();
+                self.stack.push(InterpreterType::Array(entries));
+                self.advance()
+            },
+            Op::objectPick => {
+                let keys = to_keys(self.pop_stack()?)?;
+                let mut obj = self.pop_stack()?.to_obj()?;
+                let mut picked = HashMap::with_capacity(keys.len());
+                for k in keys {
+                    if let Some(v) = obj.remove(&k) {
+                        picked.insert(k, v);
+                    }
+                }
+                self.stack.push(InterpreterType::Object(Obj(picked)));
+                self.advance()
+            },
+            Op::objectOmit => {
+                let keys = to_keys(self.pop_stack()?)?;
+                let mut obj = self.pop_stack()?.to_obj()?;
+                for k in keys {
+                    obj.remove(&k);
+                }
+                self.stack.push(InterpreterType::Object(Obj(obj)));
+                self.advance()
+            },
+            // Paths are arrays of field names and array indexes. A field or index that isn't there yields none, but indexing
+            // into a value that isn't an object or array, or indexing with the wrong kind of key, is an error.
+            Op::getPath{negative_indexes} => {
+                let path = self.pop_stack()?.to_array()?;
+                let mut orig = self.pop_stack()?;
+                let mut target = Some(&mut orig);
+                for f in path {
+                    target = match target {
+                        Some(t) => t.get_with(f, *negative_indexes)?,
+                        None => None
+                    };
+                }
+                self.stack.push(match target {
+                    Some(t) => t.clone(),
+                    None => InterpreterType::None
+                });
+                self.advance()
+            },
+            Op::setPath{negative_indexes} => {
+                let set_to = self.pop_stack()?;
+                let path = self.pop_stack()?.to_array()?;
+                self.last_stack()?.set_with(path, set_to, *negative_indexes)?;
+                self.advance()
+            },
             Op::getKeys => {                
                 let mut obj = self.pop_stack()?.to_obj()?;
                 let keys = obj.drain().map(|(k, v)| InterpreterType::string(k)).collect();
@@ -789,12 +1664,13 @@ impl <'a> Context<'a>  {
             },
             Op::invoke{name, args} => {                
                 let args = self.stack.split_off(self.stack.len() - *args as usize);
-                let next_ops = globals.fns.get(name).safe_unwrap()?;
-                let cntxt = Context::new(next_ops, args);
-                let res = conduit_byte_code_interpreter_internal(
-                    cntxt,
-                    globals
-                ).await?;
+                let next = globals.fns.get(name).safe_unwrap()?;
+                let nested = globals.nested()?;
+                let res = match execute_procedure(name, next, args, &nested).await? {
+                    Output::Value(v) => v,
+                    Output::Stream(_) => return Err(format!("Cannot return a cursor from invoked function {}", name)),
+                    Output::Rejected(reason) => return Ok(ContextState::Reject(reason))
+                };
                 self.stack.push(res);
                 self.advance()        
             },
@@ -823,25 +1699,41 @@ impl <'a> Context<'a>  {
                     InterpreterType::Object(o) => o.0,
                     _ => return Err("Require an object for signing".to_string())
                 };
-                let name_value = obj.remove("_name").safe_unwrap()?.to_str()?;
-                
-                let mut hasher = DefaultHasher::new();
-                hasher.write(name_value.as_bytes());
-                match obj.get("_state") {
-                    Some(state) => state.hash(&mut hasher),
-                    _ => {}
-                };
-                let msg: [u8; 8] = hasher.finish().to_be_bytes();
-                let sig: [u8; 64] = ed25519::signature(&msg, globals.private_key);
-                if !ed25519::verify(&msg, globals.public_key, &sig) {
-                    return Err(format!("Public key cannot validate signature."));
-                }
-                let all: Vec<InterpreterType> = sig.iter().map(|i| InterpreterType::int(*i as i64)).collect();
-                obj.insert("_sig".to_string(), InterpreterType::Array(all));
-                obj.insert("_name".to_string(), InterpreterType::string(name_value));
+                roles::sign(&mut obj, globals.keys, globals.deployment)?;
                 self.stack.push(InterpreterType::Object(Obj(obj)));
                 self.advance()
             },        
+            // Revocation lists are stores of `{jti, exp}` documents. The expiry lets old entries be cleaned up.
+            Op::revokeRole(store) => {
+                let role = self.pop_stack()?;
+                let mut entry = HashMap::with_capacity(2);
+                entry.insert("jti".to_string(), InterpreterType::string(roles::token_id(&role)?));
+                entry.insert("exp".to_string(), match role {
+                    InterpreterType::Object(mut o) => o.0.remove("_exp").unwrap_or(InterpreterType::None),
+                    _ => InterpreterType::None
+                });
+                let db = globals.db.safe_unwrap()?;
+                storage::append(db, store, &InterpreterType::Object(Obj(entry)), &globals.validator(store)?).await?;
+                self.advance()
+            },
+            Op::roleRevoked(store) => {
+                let role = self.pop_stack()?;
+                let revoked = match roles::token_id(&role) {
+                    Ok(id) => {
+                        let mut filter = HashMap::with_capacity(1);
+                        filter.insert("jti".to_string(), InterpreterType::string(id));
+                        let db = globals.db.safe_unwrap()?;
+                        match storage::measure(db, store, &filter).await? {
+                            InterpreterType::int(n) => n > 0,
+                            _ => return Err("Expected a count".to_string())
+                        }
+                    },
+                    // Roles from before token ids can't be revoked.
+                    Err(_) => false
+                };
+                self.stack.push(InterpreterType::bool(revoked));
+                self.advance()
+            },
             Op::getType => {                
                 let val = self.pop_stack()?;
                 let s = match val {
@@ -851,16 +1743,179 @@ impl <'a> Context<'a>  {
                     InterpreterType::double(_) => "doub",
                     InterpreterType::Array(_) => "arr",
                     InterpreterType::string(_) => "str",
-                    InterpreterType::Object(_) => "obj"
+                    InterpreterType::Object(_) => "obj",
+                    InterpreterType::decimal(_) => "dec",
+                    InterpreterType::timestamp(_) => "time",
+                    InterpreterType::bytes(_) => "bytes"
                 };
                 self.stack.push(InterpreterType::string(s.to_string()));
                 self.advance()
         
+            },
+            Op::queryStorePage{store, project, options} => {
+                let after = match self.pop_stack()? {
+                    InterpreterType::None => None,
+                    InterpreterType::string(s) => Some(s),
+                    _ => return Err("Expected a page token or none".to_string())
+                };
+                let filter = self.pop_stack()?.to_obj()?;
+                let db = globals.db.safe_unwrap()?;
+                let res = storage::query_page(db, store, &project.0, &filter, options, after.as_deref()).await?;
+                self.stack.push(res);
+                self.advance()
+            },
+            Op::enforceSchemaOrReject{schema, heap_pos} => {
+                let v = self.heap.get(*heap_pos as usize).safe_unwrap()?;
+                let violations = match schema.sanitize(v, globals.schemas, &globals.verifier()) {
+                    Ok(sanitized) => {
+                        self.heap[*heap_pos as usize] = sanitized;
+                        vec![]
+                    },
+                    Err(violations) => violations
+                };
+                if violations.len() > 0 {
+                    let mut reason = HashMap::with_capacity(2);
+                    reason.insert("error".to_string(), InterpreterType::string("invalid input".to_string()));
+                    reason.insert("violations".to_string(), InterpreterType::Array(
+                        violations.iter().map(|v| InterpreterType::string(v.to_string())).collect()
+                    ));
+                    return Ok(ContextState::Reject(InterpreterType::Object(Obj(reason))))
+                }
+                self.advance()
+            },
+            Op::aggregateStore{store, pipeline} => {
+                let db = globals.db.safe_unwrap()?;
+                let res = storage::aggregate(db, store, pipeline).await?;
+                self.stack.push(res);
+                self.advance()
+            },
+            Op::aggregateArray(pipeline) => {
+                let docs = self.pop_stack()?.to_array()?;
+                let sources = self.pop_stack()?.to_obj()?;
+                let res = aggregation::evaluate(pipeline, docs, &sources)?;
+                self.stack.push(InterpreterType::Array(res));
+                self.advance()
+            },
+            Op::openStoreCursor(param0, param1) => {
+                let db = globals.db.safe_unwrap()?;
+                let cursor = storage::query_stream(db, &param0, &param1.0, &self.pop_stack()?.to_obj()?).await?;
+                self.cursors.push(Some(cursor));
+                self.stack.push(InterpreterType::int((self.cursors.len() - 1) as i64));
+                self.advance()
+            },
+            Op::nextInCursor => {
+                let index = self.pop_stack()?.to_handle()?;
+                let cursor = match self.cursors.get_mut(index) {
+                    Some(Some(c)) => c,
+                    _ => return Err("Cursor does not exist".to_string())
+                };
+                let next = match cursor.next().await {
+                    Some(v) => v?,
+                    None => InterpreterType::None
+                };
+                self.stack.push(next);
+                self.advance()
+            },
+            Op::returnCursor => {
+                let index = self.pop_stack()?.to_handle()?;
+                let cursor = match self.cursors.get_mut(index) {
+                    Some(c) => c.take().safe_unwrap()?,
+                    None => return Err("Cursor does not exist".to_string())
+                };
+                Ok(ContextState::Stream(cursor))
+            },
+            Op::now => {
+                self.stack.push(InterpreterType::timestamp(Timestamp(Utc::now())));
+                self.advance()
+            },
+            Op::formatTimestamp(format) => {
+                let t = match self.pop_stack()? {
+                    InterpreterType::timestamp(t) => t,
+                    _ => return Err("Expected a timestamp".to_string())
+                };
+                let mut formatted = String::new();
+                if format.len() == 0 {
+                    formatted = t.0.to_rfc3339_opts(SecondsFormat::Millis, true);
+                } else if write!(formatted, "{}", t.0.format(format)).is_err() {
+                    return Err(format!("Invalid timestamp format {}", format))
+                }
+                self.stack.push(InterpreterType::string(formatted));
+                self.advance()
+            },
+            // An empty format reads RFC 3339. Formats without a zone are read as UTC.
+            Op::parseTimestamp(format) => {
+                let s = self.pop_stack()?.to_str()?;
+                let parsed = if format.len() == 0 {
+                    DateTime::parse_from_rfc3339(&s).map(|d| d.with_timezone(&Utc))
+                } else {
+                    match DateTime::parse_from_str(&s, format) {
+                        Ok(d) => Ok(d.with_timezone(&Utc)),
+                        Err(_) => NaiveDateTime::parse_from_str(&s, format).map(|d| DateTime::from_utc(d, Utc))
+                    }
+                };
+                match parsed {
+                    Ok(d) => self.stack.push(InterpreterType::timestamp(Timestamp(d))),
+                    Err(e) => return Err(format!("Could not parse timestamp {}: {}", s, e))
+                };
+                self.advance()
+            },
+            Op::bytesToString(encoding) => {
+                let b = match self.pop_stack()? {
+                    InterpreterType::bytes(b) => b.0,
+                    _ => return Err("Expected bytes".to_string())
+                };
+                let s = match encoding.as_str() {
+                    "utf8" => match String::from_utf8(b) {
+                        Ok(s) => s,
+                        Err(e) => return Err(format!("Bytes are not utf8: {}", e))
+                    },
+                    "base64" => base64::encode(&b),
+                    "hex" => b.iter().map(|byte| format!("{:02x}", byte)).collect(),
+                    _ => return Err(format!("Unknown encoding {}", encoding))
+                };
+                self.stack.push(InterpreterType::string(s));
+                self.advance()
+            },
+            Op::stringToBytes(encoding) => {
+                let s = self.pop_stack()?.to_str()?;
+                let b = match encoding.as_str() {
+                    "utf8" => s.into_bytes(),
+                    "base64" => match base64::decode(&s) {
+                        Ok(b) => b,
+                        Err(e) => return Err(format!("Invalid base64: {}", e))
+                    },
+                    "hex" => {
+                        if s.len() % 2 != 0 {
+                            return Err("Hex strings must have an even length".to_string())
+                        }
+                        let mut b = Vec::with_capacity(s.len() / 2);
+                        for i in (0..s.len()).step_by(2) {
+                            match s.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()) {
+                                Some(byte) => b.push(byte),
+                                None => return Err(format!("Invalid hex string {}", s))
+                            };
+                        }
+                        b
+                    },
+                    _ => return Err(format!("Unknown encoding {}", encoding))
+                };
+                self.stack.push(InterpreterType::bytes(Binary(b)));
+                self.advance()
+            },
+            Op::toDecimal => {
+                let d = self.pop_stack()?.to_decimal()?;
+                self.stack.push(InterpreterType::decimal(Decimal(d)));
+                self.advance()
+            },
+            Op::roundDecimal{places, mode} => {
+                let d = self.pop_stack()?.to_decimal()?;
+                self.stack.push(InterpreterType::decimal(Decimal(d.round_dp_with_strategy(*places, mode.strategy()))));
+                self.advance()
             }
         }
     }
 }
 
 export! {
-    Op => "ops.ts"
+    Op, Procedure, Callback, RoundingMode, QueryOptions, SortKey, Stage, GroupField, Accumulator, Store, Index => "ops.ts"
 }
\ No newline at end of file
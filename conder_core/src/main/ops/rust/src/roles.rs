@@ -0,0 +1,248 @@
+// Role signatures.
+//
+// A role is an object with a `_name`, an optional `_state`, an `_sig` array of signature bytes and
+// an `_sigv` version tag. Version 3 roles also carry claims that are covered by the signature:
+// - `_iat`: when the role was signed.
+// - `_exp`: an optional timestamp after which the role is rejected.
+// - `_aud`: the deployment the role is for, which defaults to the signing deployment.
+// - `_jti`: a unique token id, which revocation lists refer to.
+//
+// Version 3 signs the SHA-256 digest of `conder-role-v3\n` followed by the canonical JSON of
+// `[name, state, {"aud", "exp", "iat", "jti"}]`: no whitespace, object keys in sorted order and
+// missing values written as null. Version 2 signed `conder-role-v2\n` and `[name, state]` the same way.
+//
+// Each role also records the `_kid` of the key that signed it so keys can be rotated. Key ids are
+// the first 8 bytes of the SHA-256 of the public key in hex. The key id isn't signed: it only picks
+// the key to check with. Roles without one predate rotation, so they're only checked against the active key.
+//
+// Roles without a version tag are version 1, which signed a `DefaultHasher` digest of the name and
+// state. That digest isn't cryptographic, so version 1 roles are only accepted when MIN_ROLE_VERSION
+// opts into them. Version 2 roles are accepted by default so roles issued before version 3 keep working
+// until reissued. They have no claims, so they never expire, aren't bound to a deployment and can't be
+// revoked; setting MIN_ROLE_VERSION to 3 rejects them once they've been reissued.
+//
+// Checking a role against a Role schema covers its signature and claims but not revocation lists, which
+// live in stores. Procedures that accept revocable roles check them with roleRevoked.
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use crypto::ed25519;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use chrono::Utc;
+use serde_json::json;
+
+use crate::data::{InterpreterType, Obj, Timestamp};
+
+pub const CURRENT_VERSION: i64 = 3;
+// Version 1 digests can be forged, so accepting them has to be asked for.
+pub const DEFAULT_MIN_VERSION: i64 = 2;
+
+// New roles are signed by the active key. Roles signed by it or any of the retired keys are accepted.
+#[derive(Clone)]
+pub struct Keyring {
+    pub key_id: String,
+    pub private_key: [u8; 64],
+    pub public_key: [u8; 32],
+    pub verification: HashMap<String, [u8; 32]>,
+    // Roles signed with an older version are rejected.
+    pub min_version: i64
+}
+
+pub fn key_id(public_key: &[u8]) -> String {
+    let mut digest = Sha256::new();
+    digest.input(public_key);
+    let mut hash = vec![0u8; digest.output_bytes()];
+    digest.result(&mut hash);
+    hash[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Keyring {
+    pub fn new(private_key: [u8; 64], public_key: [u8; 32], retired: Vec<[u8; 32]>, min_version: i64) -> Keyring {
+        let mut verification: HashMap<String, [u8; 32]> = retired.into_iter().map(|k| (key_id(&k), k)).collect();
+        let id = key_id(&public_key);
+        verification.insert(id.clone(), public_key);
+        Keyring {
+            key_id: id,
+            private_key,
+            public_key,
+            verification,
+            min_version
+        }
+    }
+}
+
+// Checks roles presented to this deployment.
+pub struct Verifier<'a> {
+    pub keys: &'a Keyring,
+    pub audience: &'a str
+}
+
+// Writes the value without whitespace and with object keys sorted. The order serde_json keeps keys in
+// depends on its features, so it isn't relied on. Whole doubles are written like ints, the way JSON.stringify
+// writes them, so a role still verifies after a client has read 1.0 and sent it back as 1.
+fn canonical(value: &serde_json::Value, out: &mut String) {
+    match value {
+        serde_json::Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonical(item, out);
+            }
+            out.push(']');
+        },
+        serde_json::Value::Object(fields) => {
+            let mut keys: Vec<&String> = fields.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::Value::String(key.clone()).to_string());
+                out.push(':');
+                canonical(&fields[key], out);
+            }
+            out.push('}');
+        },
+        serde_json::Value::Number(n) => match n.as_f64() {
+            Some(d) if !n.is_i64() && !n.is_u64() && d.fract() == 0.0 && d.abs() < 9.2e18 => out.push_str(&(d as i64).to_string()),
+            _ => out.push_str(&n.to_string())
+        },
+        scalar => out.push_str(&scalar.to_string())
+    }
+}
+
+fn digest(prefix: &[u8], signed: serde_json::Value) -> Vec<u8> {
+    let mut text = String::new();
+    canonical(&signed, &mut text);
+    let mut digest = Sha256::new();
+    digest.input(prefix);
+    digest.input(text.as_bytes());
+    let mut msg = vec![0u8; digest.output_bytes()];
+    digest.result(&mut msg);
+    msg
+}
+
+fn message(version: i64, role: &HashMap<String, InterpreterType>) -> Result<Vec<u8>, String> {
+    let name = match role.get("_name") {
+        Some(InterpreterType::string(s)) => s,
+        _ => return Err("Roles require a name".to_string())
+    };
+    let state = role.get("_state");
+    match version {
+        1 => {
+            let mut hasher = DefaultHasher::new();
+            hasher.write(name.as_bytes());
+            match state {
+                Some(state) => state.hash(&mut hasher),
+                None => {}
+            };
+            Ok(hasher.finish().to_be_bytes().to_vec())
+        },
+        2 => Ok(digest(b"conder-role-v2\n", json!([name, state]))),
+        3 => Ok(digest(b"conder-role-v3\n", json!([name, state, {
+            "aud": role.get("_aud"),
+            "exp": role.get("_exp"),
+            "iat": role.get("_iat"),
+            "jti": role.get("_jti")
+        }]))),
+        _ => Err(format!("unknown signature version {}", version))
+    }
+}
+
+// Signs the role in place with the current version. The caller may set `_exp` and `_aud`; the rest of the claims are filled in here.
+pub fn sign(role: &mut HashMap<String, InterpreterType>, keys: &Keyring, audience: &str) -> Result<(), String> {
+    match role.get("_exp") {
+        None | Some(InterpreterType::timestamp(_)) => {},
+        _ => return Err("A role's expiry must be a timestamp".to_string())
+    };
+    match role.get("_aud") {
+        Some(InterpreterType::string(_)) => {},
+        None => {role.insert("_aud".to_string(), InterpreterType::string(audience.to_string()));},
+        _ => return Err("A role's audience must be a string".to_string())
+    };
+    role.insert("_iat".to_string(), InterpreterType::timestamp(Timestamp(Utc::now())));
+    role.insert("_jti".to_string(), InterpreterType::string(bson::oid::ObjectId::new().to_hex()));
+    role.insert("_sigv".to_string(), InterpreterType::int(CURRENT_VERSION));
+    let msg = message(CURRENT_VERSION, role)?;
+    let sig: [u8; 64] = ed25519::signature(&msg, &keys.private_key);
+    if !ed25519::verify(&msg, &keys.public_key, &sig) {
+        return Err(format!("Public key cannot validate signature."));
+    }
+    role.insert("_sig".to_string(), InterpreterType::Array(sig.iter().map(|i| InterpreterType::int(*i as i64)).collect()));
+    role.insert("_kid".to_string(), InterpreterType::string(keys.key_id.clone()));
+    Ok(())
+}
+
+impl<'a> Verifier<'a> {
+    // Explains why the role can't be accepted.
+    pub fn verify(&self, role: &Obj) -> Result<(), String> {
+        let version = match role.0.get("_sigv") {
+            Some(InterpreterType::int(v)) => *v,
+            None => 1,
+            _ => return Err("bad signature version".to_string())
+        };
+        if version < self.keys.min_version {
+            return Err(format!("signature version {} is no longer accepted", version))
+        }
+        let given_signature = match role.0.get("_sig") {
+            Some(InterpreterType::Array(a)) => {
+                let mut results = Vec::with_capacity(a.len());
+                for i in a {
+                    let u: u8 = match i {
+                        InterpreterType::int(_i) => match (*_i).try_into() {
+                            Ok(v) => v,
+                            Err(_) => return Err("bad signature".to_string())
+                        },
+                        _ => return Err("bad signature".to_string())
+                    };
+                    results.push(u);
+                }
+                results
+            },
+            _ => return Err("bad signature".to_string())
+        };
+        if given_signature.len() != 64 {
+            return Err("bad signature".to_string())
+        }
+        let candidates: Vec<&[u8; 32]> = match role.0.get("_kid") {
+            Some(InterpreterType::string(kid)) => match self.keys.verification.get(kid) {
+                Some(key) => vec![key],
+                None => return Err(format!("signed by unknown key {}", kid))
+            },
+            None => vec![&self.keys.public_key],
+            _ => return Err("bad key id".to_string())
+        };
+        let msg = message(version, &role.0)?;
+        if !candidates.iter().any(|key| ed25519::verify(&msg, &key[..], given_signature.as_slice())) {
+            return Err("bad signature".to_string())
+        }
+        // Older versions have no claims to check. MIN_ROLE_VERSION decides whether they're accepted at all.
+        if version < 3 {
+            return Ok(())
+        }
+        match role.0.get("_exp") {
+            Some(InterpreterType::timestamp(exp)) if exp.0 <= Utc::now() => return Err("role expired".to_string()),
+            _ => {}
+        };
+        match role.0.get("_aud") {
+            Some(InterpreterType::string(aud)) if aud != self.audience => Err(format!("role is for {}", aud)),
+            _ => Ok(())
+        }
+    }
+}
+
+// The id revocation lists refer to. Roles from before version 3 don't have one.
+pub fn token_id(role: &InterpreterType) -> Result<String, String> {
+    match role {
+        InterpreterType::Object(o) => match o.0.get("_jti") {
+            Some(InterpreterType::string(id)) => Ok(id.clone()),
+            _ => Err("Role does not have a token id".to_string())
+        },
+        _ => Err("Expected a role".to_string())
+    }
+}
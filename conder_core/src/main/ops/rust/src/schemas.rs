@@ -1,17 +1,25 @@
 use ts_rs::{TS, export};
 use std::collections::HashMap;
+use std::fmt;
 use std::fmt::Debug;
 use serde::{Deserialize, Serialize, Serializer, Deserializer};
+use serde::de::Error;
 use std::any::TypeId;
 use std::convert::TryInto;
 use std::hash::{Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
 use crypto::ed25519;
 
-use crate::data::{InterpreterType, Obj};
+use regex::Regex;
+
+use crate::data::{InterpreterType, Obj, Decimal, Timestamp, Binary};
+use chrono::{DateTime, Utc};
+use rust_decimal::prelude::ToPrimitive;
+use crate::aggregation;
+use crate::roles::Verifier;
 
 #[derive(Clone)]
-pub struct ObjSchema(HashMap<String, Schema>);
+pub struct ObjSchema(pub HashMap<String, Schema>);
 
 impl TS for  ObjSchema {
     fn name() -> String {
@@ -31,6 +39,12 @@ impl TS for  ObjSchema {
     }
 }
 
+impl Serialize for ObjSchema {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
 impl<'de> Deserialize<'de> for ObjSchema {
     fn deserialize<D>(deserializer: D) ->  Result<Self, D::Error> where D: Deserializer<'de>{
         let data = HashMap::deserialize(deserializer)?;
@@ -38,25 +52,144 @@ impl<'de> Deserialize<'de> for ObjSchema {
     }
 }
 
-#[derive(Deserialize, Clone, TS)]
+#[derive(Serialize, Deserialize, Clone, TS)]
 #[serde(tag = "kind", content= "data")]
 pub enum Schema {
     Object(ObjSchema),
+    PolicyObject(ObjSchema, ObjectPolicy),
     Role(String, Vec<Schema>),
     Array(Vec<Schema>),
     Union(Vec<Schema>),
     Map(Vec<Schema>),
+    Refined(Vec<Schema>, Vec<Constraint>),
     TypeAlias(String),
     double,
     int,
     string,
     bool,
+    decimal,
+    timestamp,
+    bytes,
     Any,
     none
 }
 
+// What to do with keys an object schema doesn't declare. Plain objects are closed.
+#[derive(Serialize, Deserialize, Clone, TS)]
+pub enum ObjectPolicy {
+    closed,
+    open,
+    strip
+}
+
+// A regular expression, written as its source. It is compiled when it's loaded so bad patterns are caught up front.
+#[derive(Clone)]
+pub struct Pattern(pub Regex);
+
+impl Pattern {
+    pub fn new(source: &str) -> Result<Pattern, String> {
+        match Regex::new(source) {
+            Ok(r) => Ok(Pattern(r)),
+            Err(e) => Err(format!("invalid pattern {}: {}", source, e))
+        }
+    }
+}
+
+impl TS for Pattern {
+    fn name() -> String {
+        return "string".to_string();
+    }
+
+    fn dependencies() -> Vec<(TypeId, String)>{
+        return vec![]
+    }
+
+    fn transparent() -> bool {
+        return false
+    }
+
+    fn inline(indent: usize) -> String {
+        return "string".to_string();
+    }
+}
 
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.as_str().serialize(serializer)
+    }
+}
 
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) ->  Result<Self, D::Error> where D: Deserializer<'de>{
+        let source = String::deserialize(deserializer)?;
+        Pattern::new(&source).map_err(D::Error::custom)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, TS)]
+#[serde(tag = "kind", content= "data")]
+pub enum Constraint {
+    min(f64),
+    max(f64),
+    minLength(u64),
+    maxLength(u64),
+    pattern(Pattern),
+    oneOf(Vec<InterpreterType>),
+    minItems(u64),
+    maxItems(u64),
+    uniqueItems
+}
+
+impl Constraint {
+    fn check(&self, value: &InterpreterType) -> Result<(), String> {
+        let passes = match (self, value) {
+            (Constraint::min(m), InterpreterType::int(i)) => *i as f64 >= *m,
+            (Constraint::min(m), InterpreterType::double(d)) => d >= m,
+            (Constraint::max(m), InterpreterType::int(i)) => *i as f64 <= *m,
+            (Constraint::max(m), InterpreterType::double(d)) => d <= m,
+            (Constraint::min(m), InterpreterType::decimal(d)) => d.0.to_f64().map_or(false, |d| d >= *m),
+            (Constraint::max(m), InterpreterType::decimal(d)) => d.0.to_f64().map_or(false, |d| d <= *m),
+            (Constraint::minLength(l), InterpreterType::string(s)) => s.chars().count() as u64 >= *l,
+            (Constraint::maxLength(l), InterpreterType::string(s)) => s.chars().count() as u64 <= *l,
+            (Constraint::pattern(p), InterpreterType::string(s)) => p.0.is_match(s),
+            (Constraint::oneOf(options), _) => options.iter().any(|o| aggregation::equal(Some(value), o)),
+            (Constraint::minItems(l), InterpreterType::Array(a)) => a.len() as u64 >= *l,
+            (Constraint::maxItems(l), InterpreterType::Array(a)) => a.len() as u64 <= *l,
+            (Constraint::uniqueItems, InterpreterType::Array(a)) => a.iter().enumerate().all(|(i, left)| 
+                a[i + 1..].iter().all(|right| !aggregation::equal(Some(left), right))
+            ),
+            (_, _) => return Err(format!("{} does not apply to {}", self.name(), type_name(value)))
+        };
+        if passes {
+            return Ok(())
+        }
+        Err(match self {
+            Constraint::min(m) => format!("must be at least {}", m),
+            Constraint::max(m) => format!("must be at most {}", m),
+            Constraint::minLength(l) => format!("must have at least {} characters", l),
+            Constraint::maxLength(l) => format!("must have at most {} characters", l),
+            Constraint::pattern(p) => format!("must match pattern {}", p.0.as_str()),
+            Constraint::oneOf(options) => format!("must be one of {}", serde_json::to_string(options).unwrap_or_default()),
+            Constraint::minItems(l) => format!("must have at least {} items", l),
+            Constraint::maxItems(l) => format!("must have at most {} items", l),
+            Constraint::uniqueItems => "items must be unique".to_string()
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Constraint::min(_) => "min",
+            Constraint::max(_) => "max",
+            Constraint::minLength(_) => "minLength",
+            Constraint::maxLength(_) => "maxLength",
+            Constraint::pattern(_) => "pattern",
+            Constraint::oneOf(_) => "oneOf",
+            Constraint::minItems(_) => "minItems",
+            Constraint::maxItems(_) => "maxItems",
+            Constraint::uniqueItems => "uniqueItems"
+        }
+    }
+}
 
 impl Schema {
     pub fn is_none(&self) -> bool {
@@ -73,126 +206,364 @@ impl Schema {
         }
     }
 
-    pub fn adheres(&self, value: &InterpreterType, schemas: &HashMap<String, Schema>, public_key: &[u8]) -> bool {
+    // Converts a single query parameter into the type this schema expects, if it can be read as one.
+    fn coerce_param(&self, raw: &str, schemas: &HashMap<String, Schema>) -> Option<InterpreterType> {
         match self {
-            Schema::Union(options) => options.into_iter().any(|o| o.adheres(value, schemas, public_key)),
-            Schema::TypeAlias(name) => match schemas.get(name) {
-                Some(t) => t.adheres(value, schemas, public_key),
+            Schema::string | Schema::Any => Some(InterpreterType::string(raw.to_string())),
+            Schema::int => raw.parse::<i64>().ok().map(InterpreterType::int),
+            Schema::double => raw.parse::<f64>().ok().map(InterpreterType::double),
+            Schema::bool => match raw {
+                "true" => Some(InterpreterType::bool(true)),
+                "false" => Some(InterpreterType::bool(false)),
+                _ => None
+            },
+            Schema::none => match raw {
+                "" | "null" => Some(InterpreterType::None),
+                _ => None
+            },
+            Schema::decimal => Decimal::parse(raw).ok().map(InterpreterType::decimal),
+            Schema::timestamp => match DateTime::parse_from_rfc3339(raw) {
+                Ok(d) => Some(InterpreterType::timestamp(Timestamp(d.with_timezone(&Utc)))),
+                Err(_) => None
+            },
+            Schema::bytes => base64::decode(raw).ok().map(|b| InterpreterType::bytes(Binary(b))),
+            Schema::Refined(inner, _) => inner[0].coerce_param(raw, schemas),
+            Schema::TypeAlias(name) => schemas.get(name).and_then(|s| s.coerce_param(raw, schemas)),
+            // Prefer a typed reading so that "5" becomes an int even when a string is also allowed.
+            Schema::Union(options) => options.iter()
+                .filter(|o| match o {Schema::string | Schema::Any => false, _ => true})
+                .find_map(|o| o.coerce_param(raw, schemas))
+                .or_else(|| options.iter().find_map(|o| o.coerce_param(raw, schemas))),
+            _ => serde_json::from_str(raw).ok()
+        }
+    }
+
+    // The schema of the items if this accepts an array.
+    fn array_item<'a>(&'a self, schemas: &'a HashMap<String, Schema>) -> Option<&'a Schema> {
+        match self {
+            Schema::Array(inner) => Some(&inner[0]),
+            Schema::Refined(inner, _) => inner[0].array_item(schemas),
+            Schema::TypeAlias(name) => schemas.get(name).and_then(|s| s.array_item(schemas)),
+            Schema::Union(options) => options.iter().find_map(|o| o.array_item(schemas)),
+            _ => None
+        }
+    }
+
+    fn field_schema<'a>(&'a self, field: &str, schemas: &'a HashMap<String, Schema>) -> Option<&'a Schema> {
+        match self {
+            Schema::Object(fields) => fields.0.get(field),
+            Schema::PolicyObject(fields, _) => fields.0.get(field),
+            Schema::Map(inner) => Some(&inner[0]),
+            Schema::Refined(inner, _) => inner[0].field_schema(field, schemas),
+            Schema::TypeAlias(name) => schemas.get(name).and_then(|s| s.field_schema(field, schemas)),
+            Schema::Union(options) => options.iter().find_map(|o| o.field_schema(field, schemas)),
+            _ => None
+        }
+    }
+
+    pub fn adheres(&self, value: &InterpreterType, schemas: &HashMap<String, Schema>, verifier: &Verifier) -> bool {
+        self.validate(value, schemas, verifier).len() == 0
+    }
+
+    // Validates the value and returns a copy with undeclared keys removed from any strip objects.
+    pub fn sanitize(&self, value: &InterpreterType, schemas: &HashMap<String, Schema>, verifier: &Verifier) -> Result<InterpreterType, Vec<Violation>> {
+        let violations = self.validate(value, schemas, verifier);
+        if violations.len() > 0 {
+            return Err(violations)
+        }
+        Ok(self.strip(value.clone(), schemas, verifier))
+    }
+
+    // Assumes the value already adheres to the schema.
+    fn strip(&self, value: InterpreterType, schemas: &HashMap<String, Schema>, verifier: &Verifier) -> InterpreterType {
+        match (self, value) {
+            (Schema::Object(fields), InterpreterType::Object(o)) => strip_fields(fields, &ObjectPolicy::closed, o, schemas, verifier),
+            (Schema::PolicyObject(fields, policy), InterpreterType::Object(o)) => strip_fields(fields, policy, o, schemas, verifier),
+            (Schema::Array(inner), InterpreterType::Array(a)) => InterpreterType::Array(
+                a.into_iter().map(|v| inner[0].strip(v, schemas, verifier)).collect()
+            ),
+            (Schema::Map(inner), InterpreterType::Object(o)) => InterpreterType::Object(Obj(
+                o.0.into_iter().map(|(k, v)| (k, inner[0].strip(v, schemas, verifier))).collect()
+            )),
+            (Schema::Refined(inner, _), v) => inner[0].strip(v, schemas, verifier),
+            (Schema::TypeAlias(name), v) => match schemas.get(name) {
+                Some(t) => t.strip(v, schemas, verifier),
+                None => v
+            },
+            (Schema::Union(options), v) => match options.iter().find(|o| o.adheres(&v, schemas, verifier)) {
+                Some(o) => o.strip(v, schemas, verifier),
+                None => v
+            },
+            // Roles are signed as they are, so they are never modified.
+            (_, v) => v
+        }
+    }
+
+    // Returns every way in which the value fails to match the schema, or nothing if it adheres.
+    pub fn validate(&self, value: &InterpreterType, schemas: &HashMap<String, Schema>, verifier: &Verifier) -> Vec<Violation> {
+        let mut violations = vec![];
+        self.check(value, schemas, verifier, "$".to_string(), &mut violations);
+        violations
+    }
+
+    fn kind_name(&self) -> String {
+        match self {
+            Schema::Object(_) => "object".to_string(),
+            Schema::PolicyObject(_, _) => "object".to_string(),
+            Schema::Role(name, _) => format!("role {}", name),
+            Schema::Array(_) => "array".to_string(),
+            Schema::Union(options) => options.iter().map(|o| o.kind_name()).collect::<Vec<String>>().join(" | "),
+            Schema::Map(_) => "map".to_string(),
+            Schema::Refined(inner, _) => inner[0].kind_name(),
+            Schema::TypeAlias(name) => name.to_string(),
+            Schema::double => "double".to_string(),
+            Schema::int => "int".to_string(),
+            Schema::string => "string".to_string(),
+            Schema::bool => "bool".to_string(),
+            Schema::decimal => "decimal".to_string(),
+            Schema::timestamp => "timestamp".to_string(),
+            Schema::bytes => "bytes".to_string(),
+            Schema::Any => "any".to_string(),
+            Schema::none => "none".to_string()
+        }
+    }
+
+    // Whether the value is the right sort of thing for this schema, ignoring its contents.
+    fn same_kind(&self, value: &InterpreterType, schemas: &HashMap<String, Schema>) -> bool {
+        match (self, value) {
+            (Schema::Object(_), InterpreterType::Object(_)) => true,
+            (Schema::PolicyObject(_, _), InterpreterType::Object(_)) => true,
+            (Schema::Map(_), InterpreterType::Object(_)) => true,
+            (Schema::Role(_, _), InterpreterType::Object(_)) => true,
+            (Schema::Array(_), InterpreterType::Array(_)) => true,
+            (Schema::Refined(inner, _), _) => inner[0].same_kind(value, schemas),
+            (Schema::TypeAlias(name), _) => match schemas.get(name) {
+                Some(t) => t.same_kind(value, schemas),
                 None => false
             },
-            Schema::Map(entry_t) => match value {
-                InterpreterType::Object(internal_value) => internal_value.0.values().all(|v| entry_t[0].adheres(v, schemas, public_key)),
-                _ => false
+            _ => false
+        }
+    }
+
+    fn check(&self, value: &InterpreterType, schemas: &HashMap<String, Schema>, verifier: &Verifier, path: String, violations: &mut Vec<Violation>) {
+        match self {
+            Schema::Union(options) => {
+                let mut candidates = vec![];
+                for o in options {
+                    let found = o.validate(value, schemas, verifier);
+                    if found.len() == 0 {
+                        return
+                    }
+                    if o.same_kind(value, schemas) {
+                        candidates.push(found);
+                    }
+                }
+                // When only one option could have been intended, its violations are the most useful.
+                if candidates.len() == 1 {
+                    for mut v in candidates.pop().unwrap() {
+                        v.path = format!("{}{}", path, &v.path[1..]);
+                        violations.push(v);
+                    }
+                } else {
+                    fail(violations, &path, format!("expected {}, got {}", self.kind_name(), type_name(value)));
+                }
+            },
+            Schema::Refined(inner, constraints) => {
+                let before = violations.len();
+                inner[0].check(value, schemas, verifier, path.clone(), violations);
+                if violations.len() > before {
+                    return
+                }
+                for c in constraints {
+                    match c.check(value) {
+                        Ok(_) => {},
+                        Err(message) => fail(violations, &path, message)
+                    };
+                }
             },
-            Schema::Object(internal_schema) => match value {
+            Schema::TypeAlias(name) => match schemas.get(name) {
+                Some(t) => t.check(value, schemas, verifier, path, violations),
+                None => fail(violations, &path, format!("unknown type {}", name))
+            },
+            Schema::Map(entry_t) => match value {
                 InterpreterType::Object(internal_value) => {
-                    let mut optionals_missing = 0;
-                    let mut adheres = true;
-                    for (k, v_schema) in &internal_schema.0 {
-                        adheres = match internal_value.0.get(k) {
-                            Some(v_value) => v_schema.adheres(v_value, schemas, public_key),
-                            None => {
-                                if v_schema.is_optional() {
-                                    optionals_missing += 1;
-                                    true 
-                                } else {
-                                    false
-                                }
-                            }
-                        };
-                        if !adheres {
-                            break
-                        }
+                    for (k, v) in &internal_value.0 {
+                        entry_t[0].check(v, schemas, verifier, field_path(&path, k), violations);
                     }
-                    adheres && internal_schema.0.len() - optionals_missing >= internal_value.0.len()
                 },
-                _ => false
+                _ => fail(violations, &path, format!("expected map, got {}", type_name(value)))
             },
+            Schema::Object(internal_schema) => check_fields(internal_schema, &ObjectPolicy::closed, value, schemas, verifier, path, violations),
+            Schema::PolicyObject(internal_schema, policy) => check_fields(internal_schema, policy, value, schemas, verifier, path, violations),
             Schema::Array(internal) => match value {
-                InterpreterType::Array(internal_value) => internal_value.iter().all(|val| internal[0].adheres(&val, schemas, public_key)),
-                _ => false
+                InterpreterType::Array(internal_value) => {
+                    for (i, val) in internal_value.iter().enumerate() {
+                        internal[0].check(val, schemas, verifier, format!("{}[{}]", path, i), violations);
+                    }
+                },
+                _ => fail(violations, &path, format!("expected array, got {}", type_name(value)))
             },
             Schema::none => match value {
-                InterpreterType::None => true,
-                _ => false
+                InterpreterType::None => {},
+                _ => fail(violations, &path, format!("expected none, got {}", type_name(value)))
             },
-            
             Schema::Role(role_name, state_schema) => {
                 let obj = match value {
                     InterpreterType::Object(o) => o,
-                    _ => return false
-                };
-                let name = match obj.0.get("_name") {
-                    Some(name_val) => match name_val {
-                        InterpreterType::string(s) => s,
-                        _ => return false
-                    },
-                    None => return false
+                    _ => return fail(violations, &path, format!("expected role {}, got {}", role_name, type_name(value)))
                 };
-                let given_signature = match obj.0.get("_sig") {
-                    Some(sig) => match sig {
-                        InterpreterType::Array(a) => {
-                            let mut results = Vec::with_capacity(a.len());
-                            for i in a {
-                                let u: u8 = match i {
-                                    InterpreterType::int(_i) => match (*_i).try_into() {
-                                        Ok(v) => v,
-                                        Err(_) => {
-                                            eprintln!("Failed to convert back to u8");
-                                            return false;
-                                        }
-                                    },
-                                    _ => return false
-                                };
-                                results.push(u);
-                            }
-                            results
-                        },
-                        _ => return false
-                    },
-                    None => return false
+                match obj.0.get("_name") {
+                    Some(InterpreterType::string(_)) => {},
+                    _ => return violations.push(Violation {path: field_path(&path, "_name"), message: "expected role name".to_string()})
                 };
-                if given_signature.len() != 64 {
-                    return false
+                if let Err(reason) = verifier.verify(obj) {
+                    return violations.push(Violation {path: field_path(&path, "_sig"), message: reason});
                 }
-                let mut hasher = DefaultHasher::new();
-                hasher.write(name.as_bytes());
-                let check_state = match obj.0.get("_state") {
-                    Some(state) => {
-                        state.hash(&mut hasher);
-                        Some(state)
-                    },
-                    None => None
+                match obj.0.get("_state") {
+                    Some(state) => state_schema[0].check(state, schemas, verifier, field_path(&path, "_state"), violations),
+                    None => state_schema[0].check(&InterpreterType::Object(Obj(HashMap::with_capacity(0))), schemas, verifier, field_path(&path, "_state"), violations)
                 };
-                let msg: [u8; 8] = hasher.finish().to_be_bytes();
-                ed25519::verify(&msg, public_key, given_signature.as_slice()) && match check_state {
-                    Some(state) => state_schema[0].adheres(state, schemas, public_key),
-                    None => state_schema[0].adheres(&InterpreterType::Object(Obj(HashMap::with_capacity(0))), schemas, public_key)
-                }
             },
-        Schema::Any => true,
+        Schema::Any => {},
         Schema::double => match value {
-            InterpreterType::double(_) => true,
-            InterpreterType::int(_) => true,
-            _ => false
+            InterpreterType::double(_) => {},
+            InterpreterType::int(_) => {},
+            _ => fail(violations, &path, format!("expected double, got {}", type_name(value)))
         },
         Schema::int => match value {
-            InterpreterType::int(_) => true,
-            _ => false    
+            InterpreterType::int(_) => {},
+            _ => fail(violations, &path, format!("expected int, got {}", type_name(value)))
         },
         Schema::string => match value {
-            InterpreterType::string(_) => true,
-            _ => false        
+            InterpreterType::string(_) => {},
+            _ => fail(violations, &path, format!("expected string, got {}", type_name(value)))
         },
         Schema::bool => match value {
-            InterpreterType::bool(_) => true,
-            _ => false
+            InterpreterType::bool(_) => {},
+            _ => fail(violations, &path, format!("expected bool, got {}", type_name(value)))
+        },
+        // Ints are exact, so they are accepted where decimals are expected. Doubles are not.
+        Schema::decimal => match value {
+            InterpreterType::decimal(_) => {},
+            InterpreterType::int(_) => {},
+            _ => fail(violations, &path, format!("expected decimal, got {}", type_name(value)))
+        },
+        Schema::timestamp => match value {
+            InterpreterType::timestamp(_) => {},
+            _ => fail(violations, &path, format!("expected timestamp, got {}", type_name(value)))
+        },
+        Schema::bytes => match value {
+            InterpreterType::bytes(_) => {},
+            _ => fail(violations, &path, format!("expected bytes, got {}", type_name(value)))
         }
         }
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub path: String,
+    pub message: String
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+fn fail(violations: &mut Vec<Violation>, path: &str, message: String) {
+    violations.push(Violation {path: path.to_string(), message});
+}
+
+pub fn type_name(value: &InterpreterType) -> &'static str {
+    match value {
+        InterpreterType::None => "none",
+        InterpreterType::int(_) => "int",
+        InterpreterType::double(_) => "double",
+        InterpreterType::bool(_) => "bool",
+        InterpreterType::string(_) => "string",
+        InterpreterType::Array(_) => "array",
+        InterpreterType::Object(_) => "object",
+        InterpreterType::decimal(_) => "decimal",
+        InterpreterType::timestamp(_) => "timestamp",
+        InterpreterType::bytes(_) => "bytes"
+    }
+}
+
+fn field_path(path: &str, field: &str) -> String {
+    if field.len() > 0 && field.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '$') {
+        format!("{}.{}", path, field)
+    } else {
+        format!("{}[{:?}]", path, field)
+    }
+}
+
+fn check_fields(fields: &ObjSchema, policy: &ObjectPolicy, value: &InterpreterType, schemas: &HashMap<String, Schema>, verifier: &Verifier, path: String, violations: &mut Vec<Violation>) {
+    let internal_value = match value {
+        InterpreterType::Object(o) => o,
+        _ => return fail(violations, &path, format!("expected object, got {}", type_name(value)))
+    };
+    for (k, v_schema) in &fields.0 {
+        match internal_value.0.get(k) {
+            Some(v_value) => v_schema.check(v_value, schemas, verifier, field_path(&path, k), violations),
+            None => if !v_schema.is_optional() {
+                violations.push(Violation {path: field_path(&path, k), message: "missing required field".to_string()});
+            }
+        };
+    }
+    if let ObjectPolicy::closed = policy {
+        for k in internal_value.0.keys() {
+            if !fields.0.contains_key(k) {
+                violations.push(Violation {path: field_path(&path, k), message: "unexpected field".to_string()});
+            }
+        }
+    }
+}
+
+fn strip_fields(fields: &ObjSchema, policy: &ObjectPolicy, value: Obj, schemas: &HashMap<String, Schema>, verifier: &Verifier) -> InterpreterType {
+    let mut stripped = HashMap::with_capacity(value.0.len());
+    for (k, v) in value.0 {
+        match fields.0.get(&k) {
+            Some(v_schema) => {stripped.insert(k, v_schema.strip(v, schemas, verifier));},
+            None => if let ObjectPolicy::strip = policy {} else {
+                stripped.insert(k, v);
+            }
+        };
+    }
+    InterpreterType::Object(Obj(stripped))
+}
+
+// Builds a procedure argument from query parameters, which always arrive as strings.
+// Values are converted to the types the input schema declares, and repeated keys become arrays.
+// Anything that cannot be converted is left as a string for validation to report.
+pub fn coerce_query(schema: Option<&Schema>, params: Vec<(String, String)>, schemas: &HashMap<String, Schema>) -> InterpreterType {
+    let mut grouped: HashMap<String, Vec<String>> = HashMap::new();
+    for (k, v) in params {
+        grouped.entry(k).or_insert(vec![]).push(v);
+    }
+    let mut obj = HashMap::with_capacity(grouped.len());
+    for (k, values) in grouped {
+        let field = schema.and_then(|s| s.field_schema(&k, schemas));
+        let coerce = |raw: String, s: Option<&Schema>| match s.and_then(|s| s.coerce_param(&raw, schemas)) {
+            Some(v) => v,
+            None => InterpreterType::string(raw)
+        };
+        let value = match field.and_then(|f| f.array_item(schemas)) {
+            Some(item) => InterpreterType::Array(values.into_iter().map(|v| coerce(v, Some(item))).collect()),
+            None => if values.len() == 1 {
+                coerce(values.into_iter().next().unwrap(), field)
+            } else {
+                InterpreterType::Array(values.into_iter().map(|v| InterpreterType::string(v)).collect())
+            }
+        };
+        obj.insert(k, value);
+    }
+    InterpreterType::Object(Obj(obj))
+}
+
+
 
 export! {
-    Schema => "schemas.ts"
+    Schema, ObjectPolicy, Constraint => "schemas.ts"
 }
\ No newline at end of file
@@ -1,43 +1,119 @@
 
-use mongodb::{Database, options, options::{ClientOptions, FindOptions, FindOneOptions, InsertManyOptions, FindOneAndUpdateOptions, ReplaceOptions}, bson, bson::{doc}, results, Client};
-use serde::{Deserialize, Serialize};
+use mongodb::{Database, options, options::{ClientOptions, FindOptions, FindOneOptions, InsertManyOptions, FindOneAndUpdateOptions, ReplaceOptions, UpdateOptions}, bson, bson::{doc}, results, Client, Cursor};
+use serde::{Deserialize, Serialize, Deserializer};
 use std::collections::HashMap;
-use futures::stream::StreamExt;
+use futures::stream::{StreamExt, BoxStream};
+use ts_rs::{TS};
 use crate::schemas::{Schema};
-use crate::data::{InterpreterType};
+use crate::data::{InterpreterType, Obj, Decimal, Timestamp, Binary};
+use crate::aggregation::{Stage, Accumulator};
+use crate::roles::Verifier;
 
 trait bsonable {
     fn to_doc(&self) -> Result<bson::Document, String>;
 }
 impl bsonable for InterpreterType {
     fn to_doc(&self) ->  Result<bson::Document, String> {
-        match bson::to_document(self) {
-            Ok(d) => Ok(d),
-            Err(e) => Err(format!("Could not produce bson: {}", e))
+        match to_bson(self)? {
+            bson::Bson::Document(d) => Ok(d),
+            _ => Err("Could not produce bson: expected an object".to_string())
         }
     }
 }
 
+// Decimals, timestamps and bytes are stored as Mongo decimals, dates and binary data rather than their wire forms.
+fn to_bson(value: &InterpreterType) -> Result<bson::Bson, String> {
+    Ok(match value {
+        InterpreterType::int(i) => bson::Bson::Int64(*i),
+        InterpreterType::double(d) => bson::Bson::Double(*d),
+        InterpreterType::bool(b) => bson::Bson::Boolean(*b),
+        InterpreterType::string(s) => bson::Bson::String(s.clone()),
+        InterpreterType::decimal(d) => bson::Bson::Decimal128(bson::Decimal128::from_str(&d.0.to_string())),
+        InterpreterType::timestamp(t) => bson::Bson::DateTime(t.0),
+        InterpreterType::bytes(b) => bson::Bson::Binary(bson::Binary {subtype: bson::spec::BinarySubtype::Generic, bytes: b.0.clone()}),
+        InterpreterType::Array(a) => {
+            let mut entries = Vec::with_capacity(a.len());
+            for v in a {
+                entries.push(to_bson(v)?);
+            }
+            bson::Bson::Array(entries)
+        },
+        InterpreterType::Object(o) => bson::Bson::Document(o.0.to_doc()?),
+        InterpreterType::None => bson::Bson::Null
+    })
+}
+
+fn from_bson(value: bson::Bson) -> Result<InterpreterType, String> {
+    Ok(match value {
+        bson::Bson::Int32(i) => InterpreterType::int(i as i64),
+        bson::Bson::Int64(i) => InterpreterType::int(i),
+        bson::Bson::Double(d) => InterpreterType::double(d),
+        bson::Bson::Boolean(b) => InterpreterType::bool(b),
+        bson::Bson::String(s) => InterpreterType::string(s),
+        bson::Bson::Decimal128(d) => InterpreterType::decimal(Decimal::parse(&d.to_string())?),
+        bson::Bson::DateTime(d) => InterpreterType::timestamp(Timestamp(d)),
+        bson::Bson::Binary(b) => InterpreterType::bytes(Binary(b.bytes)),
+        bson::Bson::Array(a) => {
+            let mut entries = Vec::with_capacity(a.len());
+            for v in a {
+                entries.push(from_bson(v)?);
+            }
+            InterpreterType::Array(entries)
+        },
+        bson::Bson::Document(d) => d.from_doc()?,
+        bson::Bson::ObjectId(id) => {
+            let mut o = HashMap::with_capacity(1);
+            o.insert("$oid".to_string(), InterpreterType::string(id.to_hex()));
+            InterpreterType::Object(Obj(o))
+        },
+        bson::Bson::Null | bson::Bson::Undefined => InterpreterType::None,
+        other => return Err(format!("Could not convert from bson value {}", other))
+    })
+}
+
 trait unbsonable {
     fn from_doc(self) -> Result<InterpreterType, String>;
 }
 impl unbsonable for bson::Document {
     fn from_doc(self) -> Result<InterpreterType, String> {
-        match bson::from_document(self) {
-            Ok(d) => Ok(d),
-            Err(e) => Err(format!("Could not convert from doc {}", e))
+        let mut o = HashMap::with_capacity(self.len());
+        for (k, v) in self {
+            o.insert(k, from_bson(v)?);
         }
+        Ok(InterpreterType::Object(Obj(o)))
     }
 }
 
 
-pub(crate) async fn append(db: &Database, storeName: &str, instance: &InterpreterType) -> Result<(), String> {
+// Checks documents against the schema of the store they are written to.
+pub(crate) struct Validator<'a> {
+    pub store: &'a str,
+    pub schema: &'a Schema,
+    pub schemas: &'a HashMap<String, Schema>,
+    pub verifier: Verifier<'a>
+}
+
+impl<'a> Validator<'a> {
+    // Returns the document as it should be written, with undeclared keys of strip objects removed.
+    fn check(&self, value: &InterpreterType) -> Result<InterpreterType, String> {
+        match self.schema.sanitize(value, self.schemas, &self.verifier) {
+            Ok(v) => Ok(v),
+            Err(violations) => Err(format!(
+                "Schema error: document does not match the schema of store {}: {}",
+                self.store,
+                violations.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(", ")
+            ))
+        }
+    }
+}
+
+pub(crate) async fn append(db: &Database, storeName: &str, instance: &InterpreterType, validator: &Validator<'_>) -> Result<(), String> {
     let collection = db.collection(&storeName);
     match instance { 
         InterpreterType::Array(v) => {
             let mut bs: Vec<bson::Document> = Vec::with_capacity(v.len());
             for entry in v {
-                bs.push(entry.to_doc()?);
+                bs.push(validator.check(entry)?.to_doc()?);
             }
             
             match collection.insert_many(bs, None).await {
@@ -45,9 +121,12 @@ pub(crate) async fn append(db: &Database, storeName: &str, instance: &Interprete
                 Err(e) => Err(format!("Failure inserting {}", e))
             }
         },
-        _ => match collection.insert_one(instance.to_doc()?, None).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!("Failure inserting {}", e)) 
+        _ => {
+            let instance = validator.check(instance)?;
+            match collection.insert_one(instance.to_doc()?, None).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("Failure inserting {}", e)) 
+            }
         }
     }
 
@@ -56,14 +135,16 @@ pub(crate) async fn append(db: &Database, storeName: &str, instance: &Interprete
 
 impl bsonable for HashMap<String, InterpreterType>  {
     fn to_doc(&self) ->  Result<bson::Document, String> {
-        match bson::to_document(self) {
-            Ok(d) => Ok(d),
-            Err(e) => Err(format!("Could not produce bson: {}", e))
+        let mut d = bson::Document::new();
+        for (k, v) in self {
+            d.insert(k.clone(), to_bson(v)?);
         }
+        Ok(d)
     }
 }
 
-pub(crate) async fn replace_one(db: &Database, storeName: &str, instance: &HashMap<String, InterpreterType>, filter: &HashMap<String, InterpreterType>, upsert: bool) -> Result<bool, String> {         
+pub(crate) async fn replace_one(db: &Database, storeName: &str, instance: &HashMap<String, InterpreterType>, filter: &HashMap<String, InterpreterType>, upsert: bool, validator: &Validator<'_>) -> Result<bool, String> {         
+    let instance = validator.check(&InterpreterType::Object(Obj(instance.clone())))?;
     let collection = db.collection(&storeName);
     match collection.replace_one(
         filter.to_doc()?,
@@ -76,17 +157,245 @@ pub(crate) async fn replace_one(db: &Database, storeName: &str, instance: &HashM
 }
 
 
-pub(crate) async fn query(db: &Database, storeName: &str, project: &HashMap<String, InterpreterType>, filter: &HashMap<String, InterpreterType>) -> Result<InterpreterType, String> {
+#[derive(Clone, TS)]
+pub struct Store {
+    pub schema: Schema,
+    pub indexes: Vec<Index>
+}
+
+#[derive(Deserialize, Clone, TS)]
+pub struct Index {
+    pub keys: Vec<SortKey>,
+    pub unique: bool,
+    pub expire_after_seconds: Option<u64>
+}
+
+// Stores may still be declared with only a schema.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoreDefinition {
+    Full{schema: Schema, #[serde(default)] indexes: Vec<Index>},
+    SchemaOnly(Schema)
+}
+
+impl<'de> Deserialize<'de> for Store {
+    fn deserialize<D>(deserializer: D) ->  Result<Self, D::Error> where D: Deserializer<'de>{
+        return Ok(match StoreDefinition::deserialize(deserializer)? {
+            StoreDefinition::Full{schema, indexes} => Store {schema, indexes},
+            StoreDefinition::SchemaOnly(schema) => Store {schema, indexes: vec![]}
+        });
+    }
+}
+
+impl Index {
+    fn name(&self) -> String {
+        let parts: Vec<String> = self.keys.iter().map(|k| format!("{}_{}", k.field, if k.ascending {1} else {-1})).collect();
+        parts.join("_")
+    }
+
+    fn to_doc(&self) -> bson::Document {
+        let mut d = doc! {
+            "key": sort_doc(&self.keys),
+            "name": self.name(),
+            "unique": self.unique
+        };
+        if let Some(seconds) = self.expire_after_seconds {
+            d.insert("expireAfterSeconds", seconds as i64);
+        }
+        d
+    }
+}
+
+// Creates any declared indexes that do not yet exist.
+// Mongo won't change the options of an existing index, so when the command is rejected the
+// error names the existing index that has to be dropped before the declared one can be created.
+pub(crate) async fn ensure_indexes(db: &Database, stores: &HashMap<String, Store>) -> Result<(), String> {
+    for (name, store) in stores {
+        if store.indexes.len() == 0 {
+            continue
+        }
+        let indexes: Vec<bson::Bson> = store.indexes.iter().map(|i| bson::Bson::Document(i.to_doc())).collect();
+        match db.run_command(doc! {"createIndexes": name.clone(), "indexes": indexes}, None).await {
+            Ok(_) => {
+                for i in &store.indexes {
+                    println!("Verified index {} on {}", i.name(), name);
+                }
+            },
+            Err(e) => return Err(match conflicting_index(db, name, &store.indexes).await {
+                Some(conflict) => conflict,
+                None => format!("Failure creating indexes on {}: {}", name, e)
+            })
+        };
+    }
+    Ok(())
+}
+
+// Finds an existing index with the name or keys of a declared one but different options.
+async fn conflicting_index(db: &Database, store: &str, declared: &Vec<Index>) -> Option<String> {
+    let listed = db.run_command(doc! {"listIndexes": store}, None).await.ok()?;
+    let batch = listed.get_document("cursor").ok()?.get_array("firstBatch").ok()?;
+    for existing in batch {
+        let existing = match existing {
+            bson::Bson::Document(d) => d,
+            _ => continue
+        };
+        let existing_name = existing.get_str("name").unwrap_or("");
+        for index in declared {
+            let keys_match = existing.get_document("key").map(|k| same_keys(k, &index.keys)).unwrap_or(false);
+            if existing_name != index.name() && !keys_match {
+                continue
+            }
+            let unique = existing.get_bool("unique").unwrap_or(false);
+            let expire = existing.get("expireAfterSeconds").and_then(bson_seconds);
+            if existing_name != index.name() || unique != index.unique || expire != index.expire_after_seconds {
+                return Some(format!(
+                    "Index {} on {} doesn't match the declared index {}; drop it so the declared index can be created",
+                    existing_name, store, index.name()
+                ))
+            }
+        }
+    }
+    None
+}
+
+fn same_keys(existing: &bson::Document, keys: &Vec<SortKey>) -> bool {
+    existing.len() == keys.len() && existing.iter().zip(keys.iter()).all(|((field, direction), key)| {
+        let ascending = match direction {
+            bson::Bson::Int32(n) => *n > 0,
+            bson::Bson::Int64(n) => *n > 0,
+            bson::Bson::Double(n) => *n > 0.0,
+            _ => return false
+        };
+        field == &key.field && ascending == key.ascending
+    })
+}
+
+fn bson_seconds(value: &bson::Bson) -> Option<u64> {
+    match value {
+        bson::Bson::Int32(n) => Some(*n as u64),
+        bson::Bson::Int64(n) => Some(*n as u64),
+        bson::Bson::Double(n) => Some(*n as u64),
+        _ => None
+    }
+}
+
+#[derive(Deserialize, Clone, TS, Default)]
+pub struct QueryOptions {
+    pub sort: Vec<SortKey>,
+    pub limit: Option<u64>,
+    pub skip: Option<u64>
+}
+
+#[derive(Deserialize, Clone, TS)]
+pub struct SortKey {
+    pub field: String,
+    pub ascending: bool
+}
+
+fn sort_doc(keys: &Vec<SortKey>) -> bson::Document {
+    let mut d = bson::Document::new();
+    for key in keys {
+        d.insert(key.field.clone(), if key.ascending {1} else {-1});
+    }
+    d
+}
+
+impl QueryOptions {
+    fn sort_doc(&self) -> Option<bson::Document> {
+        if self.sort.len() == 0 {
+            return None
+        }
+        Some(sort_doc(&self.sort))
+    }
+
+    // Builds a filter selecting documents that sort strictly after the token's position.
+    // Ties on the sort keys are broken by `_id`, which pages are also sorted by.
+    fn after(&self, token: &PageToken) -> Result<bson::Document, String> {
+        if self.sort.len() == 0 || token.values.len() != self.sort.len() {
+            return Err("Page token does not match the sort keys".to_string())
+        }
+        let mut clauses = Vec::with_capacity(self.sort.len() + 1);
+        for i in 0..=self.sort.len() {
+            let mut clause = bson::Document::new();
+            for (prior, value) in self.sort[..i].iter().zip(token.values.iter()) {
+                clause.insert(prior.field.clone(), to_bson(value)?);
+            }
+            let mut cmp = bson::Document::new();
+            match self.sort.get(i) {
+                Some(key) => {
+                    cmp.insert(if key.ascending {"$gt"} else {"$lt"}, to_bson(&token.values[i])?);
+                    clause.insert(key.field.clone(), cmp);
+                },
+                None => {
+                    cmp.insert("$gt", token.id.clone());
+                    clause.insert("_id", cmp);
+                }
+            };
+            clauses.push(bson::Bson::Document(clause));
+        }
+        let mut d = bson::Document::new();
+        d.insert("$or", clauses);
+        Ok(d)
+    }
+
+    fn page_token(&self, last: &InterpreterType, id: bson::Bson) -> Result<String, String> {
+        let mut values = Vec::with_capacity(self.sort.len());
+        for key in &self.sort {
+            let mut target = last;
+            for f in key.field.split('.') {
+                target = match target {
+                    InterpreterType::Object(o) => match o.0.get(f) {
+                        Some(v) => v,
+                        None => return Err(format!("Sort key {} must be included in the projection to paginate", key.field))
+                    },
+                    _ => return Err(format!("Sort key {} must be included in the projection to paginate", key.field))
+                };
+            }
+            values.push(target.clone());
+        }
+        match serde_json::to_vec(&PageToken {values, id}) {
+            Ok(v) => Ok(base64::encode_config(&v, base64::URL_SAFE_NO_PAD)),
+            Err(e) => Err(format!("Could not produce page token: {}", e))
+        }
+    }
+}
+
+// The sort key values and `_id` of the last document on a page.
+#[derive(Serialize, Deserialize)]
+struct PageToken {
+    values: Vec<InterpreterType>,
+    id: bson::Bson
+}
+
+fn decode_page_token(token: &str) -> Result<PageToken, String> {
+    let bytes = match base64::decode_config(token, base64::URL_SAFE_NO_PAD) {
+        Ok(b) => b,
+        Err(e) => return Err(format!("Invalid page token: {}", e))
+    };
+    match serde_json::from_slice(&bytes) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(format!("Invalid page token: {}", e))
+    }
+}
+
+async fn find(db: &Database, storeName: &str, project: &HashMap<String, InterpreterType>, filter: bson::Document, query_options: &QueryOptions) -> Result<Cursor, String> {
     let collection = db.collection(&storeName);
     let mut projection = project.to_doc()?;
     projection.insert("_id", false);
-    let options = FindOptions::builder().projection(Some(projection)).build();
+    let options = FindOptions::builder()
+        .projection(Some(projection))
+        .sort(query_options.sort_doc())
+        .limit(query_options.limit.map(|l| l as i64))
+        .skip(query_options.skip.map(|s| s as i64))
+        .build();
 
-    let mut res = match collection.find(filter.to_doc()?, options).await {
-        Ok(c) => c,
-        Err(e) => return Err(format!("Failure: {}", e))
-    };
+    match collection.find(filter, options).await {
+        Ok(c) => Ok(c),
+        Err(e) => Err(format!("Failure: {}", e))
+    }
+}
 
+async fn collect(mut res: Cursor) -> Result<Vec<InterpreterType>, String> {
     let mut ret = vec![];
     while let Some(v) = res.next().await {
         match v {
@@ -94,8 +403,154 @@ pub(crate) async fn query(db: &Database, storeName: &str, project: &HashMap<Stri
             Err(e) => return Err(format!("Could not produce valid type: {}", e))
         };
     }
-    
-    return Ok(InterpreterType::Array(ret))
+    Ok(ret)
+}
+
+pub(crate) async fn query(db: &Database, storeName: &str, project: &HashMap<String, InterpreterType>, filter: &HashMap<String, InterpreterType>, options: &QueryOptions) -> Result<InterpreterType, String> {
+    let res = find(db, storeName, project, filter.to_doc()?, options).await?;
+    return Ok(InterpreterType::Array(collect(res).await?))
+}
+
+// Returns {items, next}, where next is a token for the following page or none if this is the last page.
+// Pages are only well defined in a stable order, so a limit requires a sort key. Without a limit every
+// matching document is returned as a single page. Skip applies to the first page.
+pub(crate) async fn query_page(db: &Database, storeName: &str, project: &HashMap<String, InterpreterType>, filter: &HashMap<String, InterpreterType>, options: &QueryOptions, after: Option<&str>) -> Result<InterpreterType, String> {
+    let sorted = options.sort.len() > 0;
+    if options.limit.is_some() && !sorted {
+        return Err("A page with a limit must be sorted".to_string())
+    }
+    let filter_doc = match after {
+        Some(_) if !sorted => return Err("Page tokens can only be used with sorted queries".to_string()),
+        Some(token) => {
+            let mut d = bson::Document::new();
+            d.insert("$and", vec![
+                bson::Bson::Document(filter.to_doc()?), 
+                bson::Bson::Document(options.after(&decode_page_token(token)?)?)
+            ]);
+            d
+        },
+        None => filter.to_doc()?
+    };
+    if options.limit == Some(0) {
+        return Err("A page must have a limit of at least 1".to_string())
+    }
+    // One extra document is read to find out whether there is another page. Only sorted queries can have a limit.
+    let mut sort = options.sort_doc();
+    if let Some(s) = sort.as_mut() {
+        s.insert("_id", 1);
+    }
+    let find_options = FindOptions::builder()
+        .projection(Some(project.to_doc()?))
+        .sort(sort)
+        .limit(options.limit.map(|l| l.saturating_add(1) as i64))
+        .skip(if after.is_none() {options.skip.map(|s| s as i64)} else {None})
+        .build();
+    let mut res = match db.collection(&storeName).find(filter_doc, find_options).await {
+        Ok(c) => c,
+        Err(e) => return Err(format!("Failure: {}", e))
+    };
+    let mut items = vec![];
+    let mut ids = vec![];
+    while let Some(v) = res.next().await {
+        let mut doc = match v {
+            Ok(doc) => doc,
+            Err(e) => return Err(format!("Could not produce valid type: {}", e))
+        };
+        ids.push(doc.remove("_id").unwrap_or(bson::Bson::Null));
+        items.push(doc.from_doc()?);
+    }
+    let next = match options.limit {
+        Some(limit) if items.len() as u64 > limit => {
+            items.truncate(limit as usize);
+            InterpreterType::string(options.page_token(&items[limit as usize - 1], ids.swap_remove(limit as usize - 1))?)
+        },
+        _ => InterpreterType::None
+    };
+    let mut page = HashMap::with_capacity(2);
+    page.insert("items".to_string(), InterpreterType::Array(items));
+    page.insert("next".to_string(), next);
+    Ok(InterpreterType::Object(Obj(page)))
+}
+
+fn field_ref(path: &str) -> bson::Bson {
+    bson::Bson::String(format!("${}", path))
+}
+
+fn accumulator_doc(acc: &Accumulator) -> bson::Document {
+    let (op, arg) = match acc {
+        Accumulator::sum(p) => ("$sum", field_ref(p)),
+        Accumulator::avg(p) => ("$avg", field_ref(p)),
+        Accumulator::min(p) => ("$min", field_ref(p)),
+        Accumulator::max(p) => ("$max", field_ref(p)),
+        Accumulator::push(p) => ("$push", field_ref(p)),
+        Accumulator::first(p) => ("$first", field_ref(p)),
+        Accumulator::count => ("$sum", bson::Bson::Int32(1))
+    };
+    let mut d = bson::Document::new();
+    d.insert(op, arg);
+    d
+}
+
+fn pipeline_docs(pipeline: &Vec<Stage>) -> Result<Vec<bson::Document>, String> {
+    let mut docs = Vec::with_capacity(pipeline.len() + 1);
+    for stage in pipeline {
+        match stage {
+            Stage::Match(filter) => docs.push(doc! {"$match": filter.0.to_doc()?}),
+            Stage::Group{by, fields} => {
+                let mut group = bson::Document::new();
+                group.insert("_id", match by.len() {
+                    0 => bson::Bson::Null,
+                    1 => field_ref(&by[0]),
+                    _ => bson::Bson::Array(by.iter().map(|b| field_ref(b)).collect())
+                });
+                // Rename the group id to _key so results look the same as the in memory evaluator.
+                let mut rename = doc! {"_id": 0, "_key": "$_id"};
+                for f in fields {
+                    group.insert(f.name.clone(), accumulator_doc(&f.acc));
+                    rename.insert(f.name.clone(), 1);
+                }
+                docs.push(doc! {"$group": group});
+                docs.push(doc! {"$project": rename});
+            },
+            Stage::Sort(keys) => docs.push(doc! {"$sort": sort_doc(keys)}),
+            Stage::Limit(n) => docs.push(doc! {"$limit": *n as i64}),
+            Stage::Skip(n) => docs.push(doc! {"$skip": *n as i64}),
+            Stage::Project(projection) => docs.push(doc! {"$project": projection.0.to_doc()?}),
+            Stage::Lookup{from, local_field, foreign_field, as_field} => {
+                docs.push(doc! {"$lookup": {
+                    "from": from.clone(),
+                    "localField": local_field.clone(),
+                    "foreignField": foreign_field.clone(),
+                    "as": as_field.clone()
+                }});
+                let mut hide = bson::Document::new();
+                hide.insert(format!("{}._id", as_field), 0);
+                docs.push(doc! {"$project": hide});
+            }
+        };
+    }
+    docs.push(doc! {"$project": {"_id": 0}});
+    Ok(docs)
+}
+
+pub(crate) async fn aggregate(db: &Database, storeName: &str, pipeline: &Vec<Stage>) -> Result<InterpreterType, String> {
+    let collection = db.collection(&storeName);
+    let res = match collection.aggregate(pipeline_docs(pipeline)?, None).await {
+        Ok(c) => c,
+        Err(e) => return Err(format!("Failure aggregating: {}", e))
+    };
+    Ok(InterpreterType::Array(collect(res).await?))
+}
+
+pub(crate) type QueryStream = BoxStream<'static, Result<InterpreterType, String>>;
+
+// Same as query, except documents are converted lazily as the cursor is consumed.
+pub(crate) async fn query_stream(db: &Database, storeName: &str, project: &HashMap<String, InterpreterType>, filter: &HashMap<String, InterpreterType>) -> Result<QueryStream, String> {
+    let res = find(db, storeName, project, filter.to_doc()?, &QueryOptions::default()).await?;
+    Ok(res.map(|v| match v {
+        Ok(doc) => doc.from_doc(),
+        Err(e) => Err(format!("Could not produce valid type: {}", e))
+    }).boxed())
 }
 
 pub(crate) async fn find_one(db: &Database, storeName: &str, project: &HashMap<String, InterpreterType>, filter: &HashMap<String, InterpreterType>) -> Result<InterpreterType, String> {
@@ -141,25 +596,226 @@ pub(crate) async fn measure(db: &Database, storeName: &str, filter: &HashMap<Str
     Ok(InterpreterType::int(d))
 }
 
-pub(crate) async fn find_and_update_one(db: &Database, storeName: &str, upsert: bool, query_doc: &InterpreterType, update_doc: &InterpreterType) -> Result<InterpreterType, String> {
+// Finds the object or array holding the last field of a dotted path, creating missing objects along the way when asked.
+fn parent_mut<'a>(doc: &'a mut InterpreterType, path: &[&str], create: bool) -> Result<Option<&'a mut InterpreterType>, String> {
+    let mut target = doc;
+    for f in &path[..path.len() - 1] {
+        target = match target {
+            InterpreterType::Object(o) => if create {
+                o.0.entry(f.to_string()).or_insert_with(|| InterpreterType::Object(Obj(HashMap::new())))
+            } else {
+                match o.0.get_mut(*f) {
+                    Some(v) => v,
+                    None => return Ok(None)
+                }
+            },
+            InterpreterType::Array(a) => match f.parse::<usize>() {
+                Ok(i) if i < a.len() => &mut a[i],
+                _ if create => return Err(format!("Cannot create field {} in an array", f)),
+                _ => return Ok(None)
+            },
+            _ if create => return Err(format!("Cannot create field {} in a value that isn't an object", f)),
+            _ => return Ok(None)
+        };
+    }
+    Ok(Some(target))
+}
+
+fn set_path(doc: &mut InterpreterType, path: &str, value: InterpreterType) -> Result<(), String> {
+    let path: Vec<&str> = path.split('.').collect();
+    let last = path[path.len() - 1];
+    match parent_mut(doc, &path, true)? {
+        Some(InterpreterType::Object(o)) => {o.0.insert(last.to_string(), value);},
+        Some(InterpreterType::Array(a)) => match last.parse::<usize>() {
+            Ok(i) if i < a.len() => a[i] = value,
+            Ok(i) if i == a.len() => a.push(value),
+            _ => return Err(format!("Cannot set {} in an array of length {}", last, a.len()))
+        },
+        _ => return Err(format!("Cannot set {} in a value that isn't an object", last))
+    };
+    Ok(())
+}
+
+// Applies an update document the way Mongo would. The supported operators are $set, $unset and $push, including
+// $push with $each, and fields may be dotted paths.
+fn apply_update(doc: &mut InterpreterType, update: &InterpreterType) -> Result<(), String> {
+    let operators = match update {
+        InterpreterType::Object(o) => &o.0,
+        _ => return Err("Expected an update document".to_string())
+    };
+    for (op, fields) in operators {
+        let fields = match fields {
+            InterpreterType::Object(f) => &f.0,
+            _ => return Err(format!("{} expects an object", op))
+        };
+        for (field, value) in fields {
+            match op.as_str() {
+                "$set" => set_path(doc, field, value.clone())?,
+                "$unset" => {
+                    let path: Vec<&str> = field.split('.').collect();
+                    let last = path[path.len() - 1];
+                    match parent_mut(doc, &path, false)? {
+                        Some(InterpreterType::Object(o)) => {o.0.remove(last);},
+                        // Mongo leaves a null in place of unset array elements.
+                        Some(InterpreterType::Array(a)) => if let Ok(i) = last.parse::<usize>() {
+                            if i < a.len() {
+                                a[i] = InterpreterType::None;
+                            }
+                        },
+                        _ => {}
+                    };
+                },
+                "$push" => {
+                    let path: Vec<&str> = field.split('.').collect();
+                    let last = path[path.len() - 1];
+                    let mut pushed = match value {
+                        InterpreterType::Object(o) if o.0.contains_key("$each") => match o.0.get("$each") {
+                            Some(InterpreterType::Array(each)) if o.0.len() == 1 => each.clone(),
+                            _ => return Err("$push only supports $each on its own".to_string())
+                        },
+                        v => vec![v.clone()]
+                    };
+                    let target = match parent_mut(doc, &path, true)? {
+                        Some(InterpreterType::Object(o)) => o.0.entry(last.to_string()).or_insert_with(|| InterpreterType::Array(vec![])),
+                        Some(InterpreterType::Array(a)) => match last.parse::<usize>() {
+                            Ok(i) if i < a.len() => &mut a[i],
+                            _ => return Err(format!("Cannot push to {}, which does not exist", field))
+                        },
+                        _ => return Err(format!("Cannot push to {}, which does not exist", field))
+                    };
+                    match target {
+                        InterpreterType::Array(a) => a.append(&mut pushed),
+                        _ => return Err(format!("Cannot push to {}, which is not an array", field))
+                    };
+                },
+                other => return Err(format!("Unsupported update operator {}", other))
+            };
+        }
+    }
+    Ok(())
+}
+
+// Upserted documents start from the query's equality conditions, as they do in Mongo.
+fn upsert_seed(query: &InterpreterType) -> Result<InterpreterType, String> {
+    let mut seed = InterpreterType::Object(Obj(HashMap::new()));
+    if let InterpreterType::Object(q) = query {
+        for (k, v) in &q.0 {
+            let is_condition = match v {
+                InterpreterType::Object(o) => o.0.keys().any(|k| k.starts_with('$')),
+                _ => false
+            };
+            if !k.starts_with('$') && !is_condition {
+                set_path(&mut seed, k, v.clone())?;
+            }
+        }
+    }
+    Ok(seed)
+}
+
+const UPDATE_ATTEMPTS: usize = 5;
+
+// Builds the document to write from the stored one, keeping the stored BSON wherever the update left a value
+// unchanged. Fields the update didn't touch keep their exact types, such as Int32s and ObjectIds.
+fn merge_raw(before: &bson::Bson, after: &InterpreterType) -> Result<bson::Bson, String> {
+    match (before, after) {
+        (bson::Bson::Document(b), InterpreterType::Object(a)) => {
+            let mut merged = bson::Document::new();
+            for (k, v) in b {
+                if let Some(updated) = a.0.get(k) {
+                    merged.insert(k.clone(), merge_raw(v, updated)?);
+                }
+            }
+            let mut added: Vec<&String> = a.0.keys().filter(|k| !b.contains_key(k.as_str())).collect();
+            added.sort();
+            for k in added {
+                merged.insert(k.clone(), to_bson(&a.0[k])?);
+            }
+            Ok(bson::Bson::Document(merged))
+        },
+        (bson::Bson::Array(b), InterpreterType::Array(a)) if b.len() == a.len() => {
+            let mut merged = Vec::with_capacity(a.len());
+            for (v, updated) in b.iter().zip(a.iter()) {
+                merged.push(merge_raw(v, updated)?);
+            }
+            Ok(bson::Bson::Array(merged))
+        },
+        // Equal numbers of different kinds, such as 1 and 1.0, still count as a change.
+        (b, a) => match from_bson(b.clone()) {
+            Ok(stored) if stored.deep_equals(a) && std::mem::discriminant(&stored) == std::mem::discriminant(a) => Ok(b.clone()),
+            _ => to_bson(a)
+        }
+    }
+}
+
+// Stores without a schema have nothing to check, so Mongo applies their updates directly and any update operator can be used.
+// Other stores apply the update in memory so the result can be checked against the store's schema before it is written;
+// the $set, $unset and $push operators are supported. The write replaces the document only if it hasn't changed since it
+// was read, and starts over if it has. Upserts insert through a single conditional upsert, so concurrent upserts that
+// match nothing can't both insert. Returns the document as written, or none if nothing matched.
+pub(crate) async fn find_and_update_one(db: &Database, storeName: &str, upsert: bool, query_doc: &InterpreterType, update_doc: &InterpreterType, validator: &Validator<'_>) -> Result<InterpreterType, String> {
     let collection = db.collection(&storeName);
-    let options = FindOneAndUpdateOptions::builder()
-        .return_document(Some(options::ReturnDocument::After))
-        .projection(Some(doc! {"_id": false}))
-        .upsert(Some(upsert))
-        .build();
-    match collection.find_one_and_update(
-        query_doc.to_doc()?, 
-        mongodb::options::UpdateModifications::Document(update_doc.to_doc()?), 
-        Some(options)).await {
-            Ok(r) => match r {
-                Some(r) => r.from_doc(),
-                None => Ok(InterpreterType::None)
+    if let Schema::Any = validator.schema {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(Some(options::ReturnDocument::After))
+            .projection(Some(doc! {"_id": false}))
+            .upsert(Some(upsert))
+            .build();
+        return match collection.find_one_and_update(
+            query_doc.to_doc()?,
+            mongodb::options::UpdateModifications::Document(update_doc.to_doc()?),
+            Some(options)).await {
+                Ok(Some(r)) => r.from_doc(),
+                Ok(None) => Ok(InterpreterType::None),
+                Err(e) => Err(format!("Failure updating: {}", e))
+        }
+    }
+    for _ in 0..UPDATE_ATTEMPTS {
+        let before = match collection.find_one(query_doc.to_doc()?, None).await {
+            Ok(b) => b,
+            Err(e) => return Err(format!("Failure updating: {}", e))
+        };
+        let mut updated = match &before {
+            Some(b) => {
+                let mut fields = b.clone();
+                fields.remove("_id");
+                fields.from_doc()?
             },
-            Err(e) => {
-                eprintln!("Failure updating: {}", e);
-                eprintln!("{:?}", update_doc);
-                Ok(InterpreterType::None)
+            None if upsert => upsert_seed(query_doc)?,
+            None => return Ok(InterpreterType::None)
+        };
+        apply_update(&mut updated, update_doc)?;
+        let updated = validator.check(&updated)?;
+        let written = match before {
+            Some(before) => {
+                let id = match before.get("_id") {
+                    Some(id) => id.clone(),
+                    None => return Err("Updated document is missing an id".to_string())
+                };
+                let replacement = match merge_raw(&bson::Bson::Document(before.clone()), &updated)? {
+                    bson::Bson::Document(d) => d,
+                    _ => return Err("Could not produce bson: expected an object".to_string())
+                };
+                let unchanged = doc! {"_id": id, "$expr": {"$eq": ["$$ROOT", {"$literal": before}]}};
+                match collection.replace_one(unchanged, replacement, None).await {
+                    Ok(r) => r.matched_count == 1,
+                    Err(e) => return Err(format!("Failure updating: {}", e))
+                }
+            },
+            // If another upsert inserted a match first, nothing is inserted here and the next attempt updates that document.
+            // The new id keeps $setOnInsert from being empty when the document has no fields.
+            None => {
+                let mut inserted = updated.to_doc()?;
+                inserted.insert("_id", bson::oid::ObjectId::new());
+                let options = UpdateOptions::builder().upsert(Some(true)).build();
+                match collection.update_one(query_doc.to_doc()?, doc! {"$setOnInsert": inserted}, Some(options)).await {
+                    Ok(r) => r.upserted_id.is_some(),
+                    Err(e) => return Err(format!("Failure inserting {}", e))
+                }
             }
+        };
+        if written {
+            return Ok(updated)
+        }
     }
-}
\ No newline at end of file
+    Err(format!("Could not update a document in {}: it kept changing while being updated", storeName))
+}